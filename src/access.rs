@@ -0,0 +1,174 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use async_std::path::Path;
+use ipnet::IpNet;
+
+/// --allow/--deny 的参数，"CIDR" 对所有路径生效，"PREFIX=CIDR" 只对该路径前缀下的请求生效，
+/// 如 "10.0.0.0/8"、"/admin/=192.168.1.0/24"、"2001:db8::/32"，单个 ip 等同于 /32 或 /128
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpRule {
+  pub prefix: Option<String>,
+  pub net: IpNet,
+}
+
+impl IpRule {
+  fn applies(&self, path: &str) -> bool {
+    self
+      .prefix
+      .as_ref()
+      .is_none_or(|prefix| Path::new(path).starts_with(prefix))
+  }
+}
+
+impl FromStr for IpRule {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (prefix, net) = match s.split_once('=') {
+      Some((prefix, net)) if prefix.starts_with('/') => (Some(prefix.to_string()), net),
+      Some(_) => return Err(format!("invalid rule \"{}\", expect \"[/PREFIX=]CIDR\"", s)),
+      None => (None, s),
+    };
+    Ok(IpRule {
+      prefix,
+      net: parse_net(net)?,
+    })
+  }
+}
+
+/// --trusted-proxy 等参数，支持 CIDR 和单个 ip
+pub fn parse_net(s: &str) -> Result<IpNet, String> {
+  let s = s.trim();
+  s.parse::<IpNet>()
+    .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+    .map_err(|_| format!("invalid ip or cidr \"{}\"", s))
+}
+
+/// ipv6 监听地址上的 ipv4 客户端形如 ::ffff:10.0.0.1，统一为 ipv4 再匹配
+fn canonical(ip: IpAddr) -> IpAddr {
+  ip.to_canonical()
+}
+
+/// 按客户端 ip 放行或拒绝请求。匹配任意适用的 deny 规则即拒绝；存在适用的 allow 规则时必须匹配其中之一。
+#[derive(Clone, Debug, Default)]
+pub struct AccessControl {
+  pub allow: Vec<IpRule>,
+  pub deny: Vec<IpRule>,
+  /// 只信任来自这些地址的 X-Forwarded-For
+  pub trusted_proxies: Vec<IpNet>,
+}
+
+impl AccessControl {
+  fn is_trusted(&self, ip: IpAddr) -> bool {
+    self.trusted_proxies.iter().any(|net| net.contains(&ip))
+  }
+
+  /// 客户端 ip。连接来自可信代理时，从右向左取 X-Forwarded-For 中第一个不是可信代理的地址。
+  /// unix socket 的连接没有地址，只能由本机的代理转发，配置了可信代理时同样信任。
+  pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+    let peer = peer.map(canonical);
+    let trusted = match peer {
+      Some(ip) => self.is_trusted(ip),
+      None => !self.trusted_proxies.is_empty(),
+    };
+    let Some(forwarded_for) = forwarded_for.filter(|_| trusted) else {
+      return peer;
+    };
+    let mut client = peer;
+    for hop in forwarded_for.rsplit(',') {
+      let Ok(ip) = hop.trim().parse::<IpAddr>().map(canonical) else {
+        // 无法解析的地址之前的内容不可信
+        break;
+      };
+      client = Some(ip);
+      if !self.is_trusted(ip) {
+        break;
+      }
+    }
+    client
+  }
+
+  /// 没有地址的客户端不匹配任何规则
+  pub fn allows(&self, ip: Option<IpAddr>, path: &str) -> bool {
+    let ip = ip.map(canonical);
+    let matches = |rule: &&IpRule| ip.is_some_and(|ip| rule.net.contains(&ip));
+    let deny = self.deny.iter().filter(|rule| rule.applies(path));
+    if deny.clone().any(|rule| matches(&rule)) {
+      return false;
+    }
+    let mut allow = self
+      .allow
+      .iter()
+      .filter(|rule| rule.applies(path))
+      .peekable();
+    allow.peek().is_none() || allow.any(|rule| matches(&rule))
+  }
+}
+
+#[test]
+fn test_access_control() {
+  let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+  let acl = AccessControl {
+    allow: vec![
+      "10.0.0.0/8".parse().unwrap(),
+      "2001:db8::/32".parse().unwrap(),
+      "/admin/=192.168.1.10".parse().unwrap(),
+    ],
+    deny: vec![
+      "10.0.0.13".parse().unwrap(),
+      "/admin/=10.1.0.0/16".parse().unwrap(),
+    ],
+    trusted_proxies: vec![],
+  };
+  assert!(acl.allows(ip("10.2.3.4"), "/"));
+  assert!(acl.allows(ip("::ffff:10.2.3.4"), "/"));
+  assert!(acl.allows(ip("2001:db8::1"), "/a.js"));
+  assert!(!acl.allows(ip("10.0.0.13"), "/"));
+  assert!(!acl.allows(ip("8.8.8.8"), "/"));
+  assert!(!acl.allows(None, "/"));
+  assert!(acl.allows(ip("192.168.1.10"), "/admin/users"));
+  assert!(!acl.allows(ip("192.168.1.10"), "/"));
+  assert!(!acl.allows(ip("10.1.2.3"), "/admin"));
+  assert!(acl.allows(ip("10.1.2.3"), "/administrator"));
+  assert!(AccessControl::default().allows(None, "/"));
+
+  assert!("/admin=bad".parse::<IpRule>().is_err());
+  assert!("admin=10.0.0.1".parse::<IpRule>().is_err());
+  assert!("10.0.0.0/33".parse::<IpRule>().is_err());
+}
+
+#[test]
+fn test_client_ip() {
+  let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+  let acl = AccessControl {
+    trusted_proxies: vec![
+      parse_net("127.0.0.1").unwrap(),
+      parse_net("10.0.0.0/8").unwrap(),
+    ],
+    ..AccessControl::default()
+  };
+  assert_eq!(ip("1.2.3.4"), acl.client_ip(ip("1.2.3.4"), Some("5.6.7.8")));
+  assert_eq!(
+    ip("5.6.7.8"),
+    acl.client_ip(ip("127.0.0.1"), Some("5.6.7.8"))
+  );
+  assert_eq!(
+    ip("5.6.7.8"),
+    acl.client_ip(ip("127.0.0.1"), Some("9.9.9.9, 5.6.7.8, 10.0.0.2"))
+  );
+  assert_eq!(
+    ip("10.0.0.3"),
+    acl.client_ip(ip("127.0.0.1"), Some("10.0.0.3"))
+  );
+  assert_eq!(
+    ip("127.0.0.1"),
+    acl.client_ip(ip("127.0.0.1"), Some("junk"))
+  );
+  assert_eq!(ip("127.0.0.1"), acl.client_ip(ip("127.0.0.1"), None));
+  assert_eq!(ip("5.6.7.8"), acl.client_ip(None, Some("5.6.7.8")));
+  assert_eq!(
+    None,
+    AccessControl::default().client_ip(None, Some("5.6.7.8"))
+  );
+}
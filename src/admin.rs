@@ -0,0 +1,192 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+
+use async_std::path::Path;
+use log::info;
+use serde_json::json;
+use tide::{Next, Request, Response, StatusCode};
+
+use crate::config::AppConfig;
+use crate::handoff::bind;
+use crate::listener::{serve, ListenAddr, ServeOptions};
+use crate::metrics::METRICS;
+use crate::shutdown::request_shutdown;
+use crate::store::purge_cache_file;
+use crate::util::constant_time_eq;
+
+/// --admin-addr 的参数，TCP 只允许回环地址，如 "127.0.0.1:9091" 或 "unix:/run/lightstatic/admin.sock"
+pub fn parse_admin_addr(s: &str) -> Result<ListenAddr, String> {
+  let addr: ListenAddr = s.parse()?;
+  if let ListenAddr::Tcp(tcp) = &addr {
+    if !tcp.ip().is_loopback() {
+      return Err(format!(
+        "admin address must be a loopback address or \"unix:PATH\", got \"{}\"",
+        s
+      ));
+    }
+  }
+  Ok(addr)
+}
+
+#[derive(Clone)]
+struct AdminState {
+  config: AppConfig,
+  token: Option<String>,
+}
+
+/// 启动管理接口。TCP 只允许绑定回环地址且必须带 token；unix socket 权限为 0600，token 可选。
+pub async fn serve_admin(
+  addr: ListenAddr,
+  token: Option<String>,
+  config: AppConfig,
+) -> std::io::Result<()> {
+  let mut app = tide::with_state(AdminState { config, token });
+  app.with(check_token);
+  app.at("/cache/refresh").post(cache_refresh);
+  app.at("/cache/purge").post(cache_purge);
+  app.at("/cache/stats").get(cache_stats);
+  app.at("/access-log").post(access_log);
+  app.at("/shutdown").post(admin_shutdown);
+  // unix socket 只允许当前用户访问
+  let listener = bind(&addr, 0o600).await?;
+  info!("Admin api available on {}", addr);
+  async_std::task::spawn(serve(app, listener, ServeOptions::default()));
+  Ok(())
+}
+
+fn check_token<'a>(
+  req: Request<AdminState>,
+  next: Next<'a, AdminState>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+  Box::pin(async move {
+    if let Some(token) = &req.state().token {
+      let authorized = req
+        .header("authorization")
+        .and_then(|v| v.as_str().strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+      if !authorized {
+        return Ok(
+          Response::builder(StatusCode::Unauthorized)
+            .header("www-authenticate", "Bearer")
+            .build(),
+        );
+      }
+    }
+    Ok(next.run(req).await)
+  })
+}
+
+async fn cache_refresh(req: Request<AdminState>) -> tide::Result {
+  let config = &req.state().config;
+  config.mime.clear_dir_cache();
+  if config.cache_stores().next().is_none() {
+    return Ok(no_cache());
+  }
+  Ok(match config.refresh_cache().await {
+    Some(files) => {
+      info!("File store refreshed with {} files by admin api", files);
+      json_response(json!({ "files": files }))
+    }
+    None => Response::builder(StatusCode::InternalServerError)
+      .body("failed to refresh file store")
+      .build(),
+  })
+}
+
+/// 从磁盘重新读取单个文件，文件已不存在时从缓存中移除。path 为请求的 url 路径，虚拟主机的文件需要带上 host。
+async fn cache_purge(req: Request<AdminState>) -> tide::Result {
+  let config = &req.state().config;
+  let Some((_, path)) = req.url().query_pairs().find(|(k, _)| k == "path") else {
+    return Ok(
+      Response::builder(StatusCode::BadRequest)
+        .body("missing path query")
+        .build(),
+    );
+  };
+  let url_path = format!("/{}", path.trim_start_matches('/'));
+  let host = req
+    .url()
+    .query_pairs()
+    .find(|(k, _)| k == "host")
+    .map(|(_, v)| v.into_owned());
+  let Some((mount, relative)) = config.find_mount(host.as_deref(), &url_path) else {
+    return Ok(Response::new(StatusCode::NotFound));
+  };
+  let Some(store) = &mount.cache_store else {
+    return Ok(no_cache());
+  };
+  if Path::new(relative)
+    .components()
+    .any(|c| !matches!(c, async_std::path::Component::Normal(_)))
+  {
+    return Ok(Response::new(StatusCode::BadRequest));
+  }
+  let file_path = mount.root_dir.join(relative);
+  let cached = purge_cache_file(store, &file_path).await;
+  Ok(json_response(json!({ "path": path, "cached": cached })))
+}
+
+async fn cache_stats(req: Request<AdminState>) -> tide::Result {
+  let Some((files, bytes)) = req.state().config.cache_stats().await else {
+    return Ok(no_cache());
+  };
+  let (hits, misses) = METRICS.cache_counts();
+  Ok(json_response(json!({
+    "files": files,
+    "bytes": bytes,
+    "hits": hits,
+    "misses": misses,
+  })))
+}
+
+/// POST /access-log?enabled=true|false
+async fn access_log(req: Request<AdminState>) -> tide::Result {
+  let enabled = match req.url().query_pairs().find(|(k, _)| k == "enabled") {
+    Some((_, v)) if v == "true" => true,
+    Some((_, v)) if v == "false" => false,
+    _ => {
+      return Ok(
+        Response::builder(StatusCode::BadRequest)
+          .body("expect enabled=true or enabled=false")
+          .build(),
+      )
+    }
+  };
+  req
+    .state()
+    .config
+    .should_log_access
+    .store(enabled, Ordering::Relaxed);
+  info!(
+    "Access log {} by admin api",
+    if enabled { "enabled" } else { "disabled" }
+  );
+  Ok(json_response(json!({ "enabled": enabled })))
+}
+
+async fn admin_shutdown(_: Request<AdminState>) -> tide::Result {
+  info!("Shutdown requested by admin api");
+  request_shutdown();
+  Ok(Response::new(StatusCode::Accepted))
+}
+
+fn no_cache() -> Response {
+  Response::builder(StatusCode::Conflict)
+    .body("file cache is not enabled, start with --cache-in-memory")
+    .build()
+}
+
+fn json_response(value: serde_json::Value) -> Response {
+  Response::builder(200)
+    .content_type(tide::http::mime::JSON)
+    .body(value.to_string())
+    .build()
+}
+
+#[test]
+fn test_admin_addr() {
+  assert!(parse_admin_addr("127.0.0.1:9091").is_ok());
+  assert!(parse_admin_addr("unix:/tmp/admin.sock").is_ok());
+  assert!(parse_admin_addr("0.0.0.0:9091").is_err());
+}
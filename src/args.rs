@@ -1,11 +1,25 @@
-use clap::Parser;
-use std::process;
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 
-use crate::util::is_empty_root_url;
+use crate::access::{parse_net, IpRule};
+use crate::admin::parse_admin_addr;
+use crate::auth::BasicAuthUser;
+use crate::fault::FaultRule;
+use crate::limit::MIN_RATE;
+use crate::listener::ListenAddr;
+use crate::log_target::{resolve_target, LogStream, LogTarget, LogTargetSpec};
+use crate::logger::{AccessLogFormat, LogFilter};
+use crate::mime::MimeOverride;
+use crate::mount::MountSpec;
+use crate::util::{normalize_base_href, parse_duration, parse_size};
+use crate::vhost::VhostSpec;
 
 #[derive(Parser, Debug)]
 #[clap(version)]
 pub struct Args {
+  #[clap(subcommand)]
+  pub command: Option<Command>,
   /// directory path to serve
   #[clap(value_parser, value_name = "PATH")]
   pub serve_path: Option<String>,
@@ -21,6 +35,18 @@ pub struct Args {
   /// port to listen (default: "8080"). if the specified port is not available, find a free port instead.
   #[clap(short, long, value_parser, default_value_t = 8080)]
   pub port: u16,
+  /// exit with error if --port is not available instead of finding a free port (default: false)
+  #[clap(long, value_parser, default_value_t = false)]
+  pub strict_port: bool,
+  /// write the port actually listened to this file, useful for scripts when a free port is chosen
+  #[clap(long, value_parser, value_name = "FILE")]
+  pub port_file: Option<String>,
+  /// listen on "ip:port", "[ipv6]:port" or "unix:/path/to/server.sock" instead of --host and --port, can be repeated
+  #[clap(long, value_parser, value_name = "ADDR", conflicts_with_all = ["host", "port"])]
+  pub listen: Vec<ListenAddr>,
+  /// file mode of unix sockets created by --listen (default: "660")
+  #[clap(long, value_parser = parse_mode, value_name = "MODE", default_value = "660")]
+  pub unix_socket_mode: u32,
   /// gzip encode response content (default: false)
   #[clap(short, long, value_parser, default_value_t = false)]
   pub gzip: bool,
@@ -42,6 +68,9 @@ pub struct Args {
   /// delay in milliseconds for response (default: "0")
   #[clap(short, long, value_parser, default_value_t = 0)]
   pub delay: u64,
+  /// inject latency, bandwidth limits or errors for matching paths, like "latency=200-800,jitter=50,bandwidth=64K,error=503@0.1,path=^/api/" (path goes last), the first matching one applies, can be repeated
+  #[clap(long, value_parser, value_name = "OPTION[,...]")]
+  pub fault: Vec<FaultRule>,
   /// store(cache) static files into memory (default: false)
   #[clap(short, long, value_parser, default_value_t = false)]
   pub cache_in_memory: bool,
@@ -51,48 +80,303 @@ pub struct Args {
   /// write logs to directory, if specified.
   #[clap(short, long, value_parser, value_name = "DIRECTORY")]
   pub log_dir: Option<String>,
+  /// log output, "stdout", "file", "syslog" or "journald", for all logs or one of them like "access=file", can be repeated (default: "file" with --log-dir, otherwise "stdout")
+  #[clap(long, value_parser, value_name = "[STREAM=]TARGET")]
+  pub log_target: Vec<LogTargetSpec>,
+  /// log level or RUST_LOG style filter like "info,lightstatic::store=debug" (default: $RUST_LOG or "info")
+  #[clap(long, value_parser, value_name = "FILTER")]
+  pub log_level: Option<LogFilter>,
+  /// rotate log file when it exceeds the size, like "100M", only effect with --log-dir
+  #[clap(long, value_parser = parse_size, value_name = "SIZE", requires = "log_dir")]
+  pub log_max_size: Option<u64>,
+  /// keep at most N rotated log files for each of info/error/access logs
+  #[clap(long, value_parser, value_name = "N", requires = "log_dir")]
+  pub log_keep: Option<usize>,
+  /// delete rotated log files older than DAYS
+  #[clap(long, value_parser, value_name = "DAYS", requires = "log_dir")]
+  pub log_max_age: Option<u64>,
+  /// gzip rotated log files, both rolled by --log-max-size and by date (default: false)
+  #[clap(long, value_parser, default_value_t = false, requires = "log_dir")]
+  pub log_compress: bool,
+  /// mime type for an extension like "glb=model/gltf-binary", overrides built-in types and --mime-file, can be repeated
+  #[clap(long, value_parser, value_name = "EXT=TYPE")]
+  pub mime: Vec<MimeOverride>,
+  /// load extra mime types from a file in apache mime.types format ("type ext1 ext2" per line)
+  #[clap(long, value_parser, value_name = "FILE")]
+  pub mime_file: Option<String>,
+  /// serve DIR under url PREFIX, with comma separated options "html5", "cache", "listing", "index=FILE" and "header=NAME:VALUE" (header options go last), can be repeated
+  #[clap(long, value_parser, value_name = "PREFIX=DIR[,OPTION...]")]
+  pub mount: Vec<MountSpec>,
+  /// serve DIR for requests whose Host is HOST, like "example.com" or "*.example.com", with the same options as --mount. other hosts use PATH and --mount, can be repeated
+  #[clap(long, value_parser, value_name = "HOST=DIR[,OPTION...]")]
+  pub vhost: Vec<VhostSpec>,
   /// server base href, useful when under nginx sub path
   #[clap(short, long, value_parser)]
   pub base_href: Option<String>,
   /// do not print access log
   #[clap(short = 'A', long, value_parser, default_value_t = false)]
   pub no_access: bool,
+  /// access log format, "default", "combined", "json" or a template like "{remote_addr} {method} {path} {status} {bytes} {duration_ms}"
+  #[clap(long, value_parser, value_name = "FORMAT", default_value = "default")]
+  pub access_log_format: AccessLogFormat,
   /// disable color log
   #[clap(short = 'C', long, value_parser, default_value_t = false)]
   pub no_color: bool,
-  /// send signal to running process, action can be "stop" or "refresh"
+  /// send signal to running process, action can be "stop", "refresh" or "upgrade". all instances unless --name or --pid-file is given
   #[clap(short, long, value_name = "ACTION")]
   pub signal: Option<String>,
+  /// instance name, used to find the instance by --signal
+  #[clap(long, value_parser = parse_name, value_name = "NAME")]
+  pub name: Option<String>,
+  /// pid file of this instance (default: "$TMPDIR/lightstatic/{NAME or pid}.pid")
+  #[clap(long, value_parser, value_name = "PATH")]
+  pub pid_file: Option<String>,
+  /// on stop, wait up to SECONDS for in-flight responses before exit, a second stop signal exits immediately
+  #[clap(long, value_parser, value_name = "SECONDS", default_value_t = 30)]
+  pub shutdown_timeout: u64,
+  /// liveness probe path like "/healthz", answered before any file logic and not access logged
+  #[clap(long, value_parser, value_name = "PATH")]
+  pub health_path: Option<String>,
+  /// readiness probe path like "/readyz", 503 until file cache is loaded or if root dir is unreadable
+  #[clap(long, value_parser, value_name = "PATH")]
+  pub ready_path: Option<String>,
+  /// expose prometheus metrics on this address, like "127.0.0.1:9090"
+  #[clap(long, value_parser, value_name = "ADDR")]
+  pub metrics_addr: Option<SocketAddr>,
+  /// admin api address, a loopback "127.0.0.1:9091" (requires --admin-token) or "unix:/path/to/admin.sock"
+  #[clap(long, value_parser = parse_admin_addr, value_name = "ADDR")]
+  pub admin_addr: Option<ListenAddr>,
+  /// bearer token required by admin api
+  #[clap(long, value_parser, value_name = "TOKEN", requires = "admin_addr")]
+  pub admin_token: Option<String>,
+  /// require http basic auth with this user, can be repeated
+  #[clap(long, value_parser, value_name = "USER:PASSWORD")]
+  pub basic_auth: Vec<BasicAuthUser>,
+  /// require http basic auth with users of an apache htpasswd file (bcrypt, {SHA} or sha-crypt), reloaded on "refresh" signal
+  #[clap(long, value_parser, value_name = "FILE")]
+  pub htpasswd: Option<String>,
+  /// only require basic auth for paths under this prefix, can be repeated (default: all paths)
+  #[clap(long, value_parser, value_name = "PREFIX")]
+  pub auth_path: Vec<String>,
+  /// realm shown in the browser login dialog
+  #[clap(
+    long,
+    value_parser,
+    value_name = "REALM",
+    default_value = "lightstatic"
+  )]
+  pub auth_realm: String,
+  /// only allow clients in this CIDR or ip, optionally only for paths under PREFIX like "/admin/=10.0.0.0/8", can be repeated
+  #[clap(long, value_parser, value_name = "[PREFIX=]CIDR")]
+  pub allow: Vec<IpRule>,
+  /// reject clients in this CIDR or ip with 403, optionally only for paths under PREFIX, takes precedence over --allow, can be repeated
+  #[clap(long, value_parser, value_name = "[PREFIX=]CIDR")]
+  pub deny: Vec<IpRule>,
+  /// trust X-Forwarded-For from this proxy CIDR or ip to find the client ip for --allow, --deny and access log, can be repeated
+  #[clap(long, value_parser = parse_net, value_name = "CIDR")]
+  pub trusted_proxy: Vec<ipnet::IpNet>,
+  /// limit requests per second of each client ip (ipv6 by /64), like "10" or "0.5" (at least 0.001), exceeding requests get 429 with Retry-After
+  #[clap(long, value_parser = parse_rate, value_name = "RATE")]
+  pub rate_limit: Option<f64>,
+  /// requests a client can send at once before --rate-limit applies (default: RATE rounded up)
+  #[clap(long, value_parser, value_name = "N", requires = "rate_limit")]
+  pub rate_burst: Option<u32>,
+  /// close new connections immediately once this many connections are open
+  #[clap(long, value_parser, value_name = "N")]
+  pub max_connections: Option<NonZeroUsize>,
+  /// answer 503 with Retry-After once this many requests are being handled at the same time
+  #[clap(long, value_parser, value_name = "N")]
+  pub max_requests: Option<NonZeroUsize>,
+  /// secret for signed urls, requests under --sign-path need a valid "?expires=...&sig=..." which "lightstatic sign" creates, --basic-auth still applies. visible in process list, prefer --sign-secret-file
+  #[clap(long, value_parser, value_name = "SECRET", global = true)]
+  pub sign_secret: Option<String>,
+  /// read the --sign-secret from this file, trailing newline is ignored
+  #[clap(
+    long,
+    value_parser,
+    value_name = "FILE",
+    global = true,
+    conflicts_with = "sign_secret"
+  )]
+  pub sign_secret_file: Option<String>,
+  /// require signed urls for paths under this prefix, like "/private" or "/" for all paths, required by --sign-secret, can be repeated
+  #[clap(long, value_parser, value_name = "PREFIX")]
+  pub sign_path: Vec<String>,
+  /// serve https with PEM certificate (chain) file, reloaded on "refresh" signal
+  #[clap(long, value_parser, value_name = "FILE", requires = "tls_key")]
+  pub tls_cert: Option<String>,
+  /// PEM private key file for --tls-cert
+  #[clap(long, value_parser, value_name = "FILE", requires = "tls_cert")]
+  pub tls_key: Option<String>,
+  /// also listen plain http on this port and redirect all requests to https
+  #[clap(long, value_parser, value_name = "PORT")]
+  pub http_redirect_port: Option<u16>,
+  /// serve https with a self-signed certificate for localhost and lan ip, cached in user config dir (default: false)
+  #[clap(
+    long,
+    value_parser,
+    default_value_t = false,
+    conflicts_with = "tls_cert"
+  )]
+  pub https_dev: bool,
+  /// enable http/2, negotiated by ALPN under https, or h2c with prior knowledge under plain http (default: false)
+  #[clap(long, value_parser, default_value_t = false)]
+  pub http2: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+  /// print a signed url for PATH like "/private/report.pdf" or a full url, using --sign-secret or --sign-secret-file
+  Sign {
+    #[clap(value_parser, value_name = "PATH")]
+    path: String,
+    /// lifetime of the url, like "3600", "30m", "12h" or "7d"
+    #[clap(
+      long,
+      value_parser = parse_duration,
+      value_name = "DURATION",
+      default_value = "1h"
+    )]
+    expires: u64,
+  },
+}
+
+impl Args {
+  pub fn tls_enabled(&self) -> bool {
+    self.tls_cert.is_some() || self.https_dev
+  }
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+  u32::from_str_radix(s, 8)
+    .ok()
+    .filter(|mode| *mode <= 0o777)
+    .ok_or_else(|| format!("invalid file mode \"{}\", expect octal like \"660\"", s))
 }
 
-pub fn get_args() -> Args {
-  let mut args = Args::parse();
-  if args.log_dir.is_some() || args.no_color {
+fn parse_rate(s: &str) -> Result<f64, String> {
+  s.parse::<f64>()
+    .ok()
+    .filter(|rate| rate.is_finite() && *rate >= MIN_RATE)
+    .ok_or_else(|| {
+      format!(
+        "invalid rate \"{}\", expect a number of at least {}",
+        s, MIN_RATE
+      )
+    })
+}
+
+/// 实例名会作为 pid 文件名，只允许字母、数字、"-"、"_" 和 "."
+fn parse_name(s: &str) -> Result<String, String> {
+  if !s.is_empty()
+    && !s.starts_with('.')
+    && s
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+  {
+    Ok(s.to_string())
+  } else {
+    Err(format!(
+      "invalid instance name \"{}\", only letters, digits, \"-\", \"_\" and \".\" are allowed",
+      s
+    ))
+  }
+}
+
+/// 解析命令行参数并校验，参数格式错误或 --help 时由 clap 输出并退出进程
+pub fn get_args() -> Result<Args, String> {
+  check_args(Args::parse())
+}
+
+/// 校验参数之间的依赖关系，返回的 Args 可以直接交给 [`crate::serve`]
+pub fn check_args(mut args: Args) -> Result<Args, String> {
+  let uses_file = [LogStream::Info, LogStream::Error, LogStream::Access]
+    .iter()
+    .any(|stream| {
+      resolve_target(&args.log_target, *stream, args.log_dir.is_some()) == LogTarget::File
+    });
+  if uses_file && args.log_dir.is_none() {
+    return Err("--log-target file requires --log-dir".to_string());
+  }
+  if args.log_dir.is_some()
+    || args.no_color
+    || args
+      .log_target
+      .iter()
+      .any(|spec| spec.target != LogTarget::Stdout)
+  {
     colored::control::set_override(false);
   }
-  if args.regex_immutable.is_some() && !args.cache_in_memory {
-    eprintln!("--regex-immutable only effect with --cache-in-memory");
-    process::exit(-1);
+  let caches = args.cache_in_memory
+    || args.mount.iter().any(|spec| spec.cache)
+    || args.vhost.iter().any(|spec| spec.mount.cache);
+  if args.regex_immutable.is_some() && !caches {
+    return Err(
+      "--regex-immutable only effect with --cache-in-memory or a cached --mount".to_string(),
+    );
   }
-  if args.signal.is_none() && args.serve_path.is_none() {
-    eprintln!("error: missing serve path\n\nUSAGE:\n    lightstatic [OPTIONS] <PATH>\n\nFor more information try --help\n");
-    process::exit(-1);
+  if args.http_redirect_port.is_some() && !args.tls_enabled() {
+    return Err("--http-redirect-port only effect with --tls-cert or --https-dev".to_string());
   }
-
-  if let Some(base_href) = &mut args.base_href {
-    if is_empty_root_url(base_href.trim()) {
-      args.base_href.take();
-    } else {
-      if !base_href.ends_with('/') {
-        base_href.push('/');
-      }
-      if !base_href.starts_with('/') {
-        let mut bh = "/".to_string();
-        bh.push_str(base_href);
-        args.base_href.replace(bh);
-      }
-    }
+  if matches!(args.admin_addr, Some(ListenAddr::Tcp(_))) && args.admin_token.is_none() {
+    return Err("--admin-addr with tcp address requires --admin-token".to_string());
+  }
+  if let Some(file) = &args.sign_secret_file {
+    let secret = std::fs::read_to_string(file)
+      .map_err(|err| format!("failed read --sign-secret-file {} due to {}", file, err))?;
+    args.sign_secret = Some(secret.trim_end_matches(['\r', '\n']).to_string());
   }
+  if args.sign_secret.as_deref() == Some("") {
+    return Err("--sign-secret must not be empty".to_string());
+  }
+  if args.command.is_some() && args.sign_secret.is_none() {
+    return Err("lightstatic sign requires --sign-secret or --sign-secret-file".to_string());
+  }
+  if args.command.is_none() && args.sign_secret.is_some() && args.sign_path.is_empty() {
+    return Err("--sign-secret requires --sign-path, like \"--sign-path /private\"".to_string());
+  }
+  if !args.sign_path.is_empty() && args.sign_secret.is_none() {
+    return Err("--sign-path requires --sign-secret or --sign-secret-file".to_string());
+  }
+  if args.signal.is_none()
+    && args.command.is_none()
+    && args.serve_path.is_none()
+    && args.mount.is_empty()
+    && args.vhost.is_empty()
+  {
+    return Err("error: missing serve path\n\nUSAGE:\n    lightstatic [OPTIONS] <PATH>\n\nFor more information try --help\n".to_string());
+  }
+
+  args.base_href = args.base_href.as_deref().and_then(normalize_base_href);
+
+  Ok(args)
+}
+
+#[test]
+fn test_check_args() {
+  let check =
+    |argv: &[&str]| check_args(Args::try_parse_from([&["lightstatic"], argv].concat()).unwrap());
+  let args = check(&["./dist", "--base-href", "app"]).unwrap();
+  assert_eq!(Some("/app/".to_string()), args.base_href);
+  assert!(check(&[]).unwrap_err().contains("missing serve path"));
+  assert!(check(&["./dist", "--regex-immutable", "\\.js$"]).is_err());
+  assert!(check(&["./dist", "--admin-addr", "127.0.0.1:9091"]).is_err());
+  assert!(check(&["--signal", "stop"]).is_ok());
 
-  args
+  assert!(check(&["./dist", "--sign-secret", "s"]).is_err());
+  assert!(check(&["./dist", "--sign-path", "/private"]).is_err());
+  let secret_file = std::env::temp_dir().join(format!("lightstatic-secret-{}", std::process::id()));
+  std::fs::write(&secret_file, "from-file\n").unwrap();
+  let secret_file = secret_file.to_string_lossy().to_string();
+  let args = check(&[
+    "./dist",
+    "--sign-secret-file",
+    &secret_file,
+    "--sign-path",
+    "/private",
+  ])
+  .unwrap();
+  assert_eq!(Some("from-file".to_string()), args.sign_secret);
+  assert!(check(&["sign", "--sign-secret-file", &secret_file, "/a"]).is_ok());
+  std::fs::remove_file(&secret_file).unwrap();
 }
@@ -0,0 +1,311 @@
+use std::io;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+
+use ahash::{AHashMap, AHashSet};
+use async_std::path::{Path, PathBuf};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{error, info};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY, SHA256};
+use tide::{Response, StatusCode};
+
+use crate::util::constant_time_eq;
+
+/// 验证通过的凭据最多缓存的个数，避免每个请求都计算一次 bcrypt
+const VERIFIED_CACHE_SIZE: usize = 1024;
+
+/// --basic-auth 的参数，如 "admin:secret"
+#[derive(Clone, Debug)]
+pub struct BasicAuthUser {
+  pub user: String,
+  pub password: String,
+}
+
+impl FromStr for BasicAuthUser {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.split_once(':') {
+      Some((user, password)) if !user.is_empty() => Ok(BasicAuthUser {
+        user: user.to_string(),
+        password: password.to_string(),
+      }),
+      _ => Err("invalid basic auth, expect \"USER:PASSWORD\"".to_string()),
+    }
+  }
+}
+
+/// 密码的存储方式，htpasswd 支持 bcrypt（htpasswd -B）、{SHA}（htpasswd -s）和 $5$/$6$ sha-crypt（htpasswd -2/-5）
+#[derive(Clone, Debug)]
+enum Credential {
+  Plain(String),
+  Bcrypt(String),
+  Sha1(Vec<u8>),
+  ShaCrypt(String),
+}
+
+impl Credential {
+  fn parse(hash: &str) -> Result<Self, String> {
+    if ["$2y$", "$2a$", "$2b$", "$2x$"]
+      .iter()
+      .any(|prefix| hash.starts_with(prefix))
+    {
+      Ok(Credential::Bcrypt(hash.to_string()))
+    } else if hash.starts_with("$5$") || hash.starts_with("$6$") {
+      Ok(Credential::ShaCrypt(hash.to_string()))
+    } else if let Some(sha1) = hash.strip_prefix("{SHA}") {
+      STANDARD
+        .decode(sha1)
+        .ok()
+        .filter(|sha1| sha1.len() == 20)
+        .map(Credential::Sha1)
+        .ok_or_else(|| "invalid {SHA} hash".to_string())
+    } else if hash.starts_with("$apr1$") {
+      Err("apr1 md5 hash is not supported, create it with \"htpasswd -B\" instead".to_string())
+    } else {
+      Err("unsupported hash, expect bcrypt, {SHA} or sha-crypt".to_string())
+    }
+  }
+
+  /// 校验的耗时排序，用于挑选用户不存在时校验的哈希
+  fn cost(&self) -> u8 {
+    match self {
+      Credential::Plain(_) => 0,
+      Credential::Sha1(_) => 1,
+      Credential::ShaCrypt(_) => 2,
+      Credential::Bcrypt(_) => 3,
+    }
+  }
+
+  /// 比较耗时只和密码长度有关，bcrypt 和 sha-crypt 由对应的库保证
+  fn verify(&self, password: &str) -> bool {
+    match self {
+      Credential::Plain(expected) => constant_time_eq(password.as_bytes(), expected.as_bytes()),
+      Credential::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+      Credential::Sha1(expected) => constant_time_eq(
+        digest(&SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes()).as_ref(),
+        expected,
+      ),
+      Credential::ShaCrypt(hash) if hash.starts_with("$5$") => {
+        sha_crypt::sha256_check(password, hash).is_ok()
+      }
+      Credential::ShaCrypt(hash) => sha_crypt::sha512_check(password, hash).is_ok(),
+    }
+  }
+}
+
+/// htpasswd 格式，每行 "user:hash"，"#" 开头为注释
+fn parse_htpasswd(content: &str) -> Result<Vec<(String, Credential)>, String> {
+  let mut users = Vec::new();
+  for (idx, line) in content.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let Some((user, hash)) = line.split_once(':') else {
+      return Err(format!("line {}: expect \"USER:HASH\"", idx + 1));
+    };
+    let credential = Credential::parse(hash)
+      .map_err(|err| format!("line {}: {} for user {}", idx + 1, err, user))?;
+    users.push((user.to_string(), credential));
+  }
+  Ok(users)
+}
+
+/// HTTP Basic 认证，paths 为空时保护所有路径，否则只保护这些路径前缀下的请求
+pub struct BasicAuth {
+  realm: String,
+  paths: Vec<String>,
+  basic_auth: Vec<BasicAuthUser>,
+  htpasswd: Option<PathBuf>,
+  users: RwLock<AHashMap<String, Credential>>,
+  /// 用户不存在时也校验一次这个哈希（取最耗时的一种），避免通过响应时间判断用户名是否存在
+  dummy: RwLock<Option<Credential>>,
+  /// 验证通过的 "user:password" 的 sha256
+  verified: Mutex<AHashSet<Vec<u8>>>,
+}
+
+impl BasicAuth {
+  /// --basic-auth 中的用户覆盖 htpasswd 中的同名用户
+  pub fn new(
+    realm: &str,
+    paths: Vec<String>,
+    basic_auth: Vec<BasicAuthUser>,
+    htpasswd: Option<PathBuf>,
+  ) -> io::Result<Self> {
+    let auth = BasicAuth {
+      realm: realm.replace('\\', "\\\\").replace('"', "\\\""),
+      paths,
+      basic_auth,
+      htpasswd,
+      users: RwLock::new(AHashMap::new()),
+      dummy: RwLock::new(None),
+      verified: Mutex::new(AHashSet::new()),
+    };
+    auth.reload()?;
+    Ok(auth)
+  }
+
+  /// 重新读取 htpasswd（SIGHUP 时），失败时保留原来的用户
+  pub fn reload(&self) -> io::Result<usize> {
+    let mut users = AHashMap::new();
+    if let Some(htpasswd) = &self.htpasswd {
+      let content = std::fs::read_to_string(htpasswd)?;
+      let parsed = parse_htpasswd(&content).map_err(|err| {
+        io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("{}: {}", htpasswd.display(), err),
+        )
+      })?;
+      users.extend(parsed);
+    }
+    for item in self.basic_auth.iter() {
+      users.insert(item.user.clone(), Credential::Plain(item.password.clone()));
+    }
+    let count = users.len();
+    *self.dummy.write().unwrap() = users.values().max_by_key(|c| c.cost()).cloned();
+    *self.users.write().unwrap() = users;
+    self.verified.lock().unwrap().clear();
+    Ok(count)
+  }
+
+  pub fn reload_htpasswd(&self) {
+    if self.htpasswd.is_none() {
+      return;
+    }
+    match self.reload() {
+      Ok(count) => info!("Basic auth reloaded with {} users", count),
+      Err(err) => error!("Failed to reload htpasswd due to {}", err),
+    }
+  }
+
+  pub fn requires_auth(&self, path: &str) -> bool {
+    self.paths.is_empty()
+      || self
+        .paths
+        .iter()
+        .any(|prefix| Path::new(path).starts_with(prefix))
+  }
+
+  /// 校验 Authorization 头，返回通过验证的用户名
+  pub async fn verify(&self, authorization: Option<&str>) -> Option<String> {
+    let (scheme, encoded) = authorization?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+      return None;
+    }
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let key = digest(&SHA256, &decoded).as_ref().to_vec();
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    if self.verified.lock().unwrap().contains(&key) {
+      return Some(user.to_string());
+    }
+    let credential = self.users.read().unwrap().get(user).cloned();
+    let (credential, known) = match credential {
+      Some(credential) => (credential, true),
+      None => (self.dummy.read().unwrap().clone()?, false),
+    };
+    let password = password.to_string();
+    // bcrypt 很耗时，不能阻塞处理请求的线程
+    let valid =
+      async_std::task::spawn_blocking(move || credential.verify(&password)).await && known;
+    if !valid {
+      return None;
+    }
+    let mut verified = self.verified.lock().unwrap();
+    if verified.len() >= VERIFIED_CACHE_SIZE {
+      verified.clear();
+    }
+    verified.insert(key);
+    Some(user.to_string())
+  }
+
+  /// 401 响应，浏览器会弹出登录框
+  pub fn challenge(&self) -> Response {
+    Response::builder(StatusCode::Unauthorized)
+      .header(
+        "www-authenticate",
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+      )
+      .body("unauthorized")
+      .build()
+  }
+}
+
+#[async_std::test]
+async fn test_basic_auth() {
+  let htpasswd = std::env::temp_dir().join(format!("lightstatic-htpasswd-{}", std::process::id()));
+  let bcrypt_hash = bcrypt::hash("b-pass", 4).unwrap();
+  let sha1 = STANDARD.encode(digest(&SHA1_FOR_LEGACY_USE_ONLY, b"s-pass"));
+  let sha512 =
+    sha_crypt::sha512_simple("c-pass", &sha_crypt::Sha512Params::new(1000).unwrap()).unwrap();
+  std::fs::write(
+    &htpasswd,
+    format!(
+      "# users\nbob:{}\nsam:{{SHA}}{}\ncarl:{}\nadmin:$2y$04$unused\n",
+      bcrypt_hash, sha1, sha512
+    ),
+  )
+  .unwrap();
+  let auth = BasicAuth::new(
+    "staging \"site\"",
+    vec!["/private/".to_string()],
+    vec!["admin:secret".parse().unwrap()],
+    Some(PathBuf::from(&htpasswd)),
+  )
+  .unwrap();
+  let header = |user_pass: &str| format!("Basic {}", STANDARD.encode(user_pass));
+  assert_eq!(
+    Some("bob".into()),
+    auth.verify(Some(&header("bob:b-pass"))).await
+  );
+  assert_eq!(
+    Some("bob".into()),
+    auth.verify(Some(&header("bob:b-pass"))).await
+  );
+  assert_eq!(None, auth.verify(Some(&header("bob:wrong"))).await);
+  assert_eq!(
+    Some("sam".into()),
+    auth.verify(Some(&header("sam:s-pass"))).await
+  );
+  assert_eq!(
+    Some("carl".into()),
+    auth.verify(Some(&header("carl:c-pass"))).await
+  );
+  assert_eq!(
+    Some("admin".into()),
+    auth.verify(Some(&header("admin:secret"))).await
+  );
+  assert_eq!(None, auth.verify(Some(&header("nobody:"))).await);
+  // 不存在的用户校验的是 bob 的 bcrypt 哈希，密码相同也不能通过
+  assert!(matches!(
+    *auth.dummy.read().unwrap(),
+    Some(Credential::Bcrypt(_))
+  ));
+  assert_eq!(None, auth.verify(Some(&header("nobody:b-pass"))).await);
+  assert_eq!(None, auth.verify(Some("Bearer abc")).await);
+  assert_eq!(None, auth.verify(None).await);
+  assert!(auth.requires_auth("/private/a.html"));
+  assert!(auth.requires_auth("/private"));
+  assert!(!auth.requires_auth("/privates"));
+  assert!(!auth.requires_auth("/"));
+  assert_eq!(
+    "Basic realm=\"staging \\\"site\\\"\", charset=\"UTF-8\"",
+    auth
+      .challenge()
+      .header("www-authenticate")
+      .unwrap()
+      .as_str()
+  );
+
+  std::fs::write(&htpasswd, "bob:$apr1$abc$def\n").unwrap();
+  assert!(auth.reload().is_err());
+  assert_eq!(
+    Some("bob".into()),
+    auth.verify(Some(&header("bob:b-pass"))).await
+  );
+  std::fs::remove_file(&htpasswd).unwrap();
+  assert!(parse_htpasswd("nocolon").is_err());
+  assert!("nopass".parse::<BasicAuthUser>().is_err());
+}
@@ -1,33 +1,403 @@
-use crate::util::resolve_path;
+use crate::access::AccessControl;
+use crate::auth::BasicAuth;
+use crate::fault::FaultRule;
+use crate::limit::{ConcurrencyLimit, RateLimit};
+use crate::logger::AccessLogFormat;
+use crate::mime::MimeTable;
+use crate::mount::{load_mounts, Mount, MountSpec};
+use crate::sign::UrlSigner;
+use crate::store::refresh_cache_store;
+use crate::tls::TlsStore;
+use crate::util::{normalize_base_href, resolve_path};
+use crate::vhost::{VhostSpec, VirtualHost};
 use crate::{args, store::FileCacheStore};
 use async_std::path::{Path, PathBuf};
 use async_std::sync::RwLock;
+use regex::Regex;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 pub struct Config {
+  /// 毫秒
   pub delay: u64,
-  pub should_log_access: bool,
-  pub root_dir: PathBuf,
+  /// 按顺序使用第一个匹配请求路径的规则
+  pub faults: Vec<FaultRule>,
+  /// 可以通过管理接口运行时开关
+  pub should_log_access: AtomicBool,
+  pub access_log_format: AccessLogFormat,
   pub base_href: Option<String>,
-  pub html5: bool,
-  pub index: String,
-  pub index_href: PathBuf,
+  /// 按前缀从长到短排序，请求由最长的匹配前缀处理。没有匹配的虚拟主机时使用
+  pub mounts: Vec<Mount>,
+  /// 按 Host 选择的站点，每个站点有自己的挂载目录
+  pub vhosts: Vec<VirtualHost>,
   pub gzip: bool,
-  pub cache_store: Option<RwLock<FileCacheStore>>,
+  pub mime: MimeTable,
+  pub auth: Option<BasicAuth>,
+  pub signer: Option<UrlSigner>,
+  pub access: AccessControl,
+  pub rate_limit: Option<RateLimit>,
+  pub max_requests: Option<Arc<ConcurrencyLimit>>,
+  pub tls: Option<Arc<TlsStore>>,
+  pub health_path: Option<String>,
+  pub ready_path: Option<String>,
+  /// build() 加载完缓存后为 true，嵌入时可以置为 false 让 ready_path 返回 503（如下线前摘除流量）
+  pub ready: AtomicBool,
 }
+
 impl Config {
-  pub fn new(args: &args::Args, cwd: &Path) -> Self {
-    let root_dir = resolve_path(cwd, Path::new(args.serve_path.as_ref().unwrap()));
-    Config {
-      delay: args.delay,
-      should_log_access: !args.no_access,
-      base_href: args.base_href.clone(),
-      html5: args.html5,
-      index_href: root_dir.join(&args.index),
-      index: args.index.clone(),
-      root_dir,
-      gzip: args.gzip,
-      cache_store: None,
+  /// 以 root_dir 为站点根目录（有 base_href 时挂载到 base_href 下），其它选项和命令行的默认值相同
+  pub fn builder(root_dir: impl Into<PathBuf>) -> ConfigBuilder {
+    ConfigBuilder {
+      root_dir: Some(root_dir.into()),
+      ..ConfigBuilder::default()
+    }
+  }
+
+  /// 处理该请求的挂载目录，以及路径在该目录下的相对路径。host 不带端口
+  pub fn find_mount<'a>(&self, host: Option<&str>, path: &'a str) -> Option<(&Mount, &'a str)> {
+    let mounts = host
+      .and_then(|host| self.vhosts.iter().find(|vhost| vhost.host.matches(host)))
+      .map_or(&self.mounts, |vhost| &vhost.mounts);
+    mounts
+      .iter()
+      .find_map(|mount| mount.relative(path).map(|relative| (mount, relative)))
+  }
+
+  /// 包括所有虚拟主机的挂载目录
+  pub fn all_mounts(&self) -> impl Iterator<Item = &Mount> {
+    self
+      .mounts
+      .iter()
+      .chain(self.vhosts.iter().flat_map(|vhost| vhost.mounts.iter()))
+  }
+
+  pub fn cache_stores(&self) -> impl Iterator<Item = &RwLock<FileCacheStore>> {
+    self
+      .all_mounts()
+      .filter_map(|mount| mount.cache_store.as_ref())
+  }
+
+  /// 所有挂载目录缓存的文件数和占用内存，没有开启缓存时返回 None
+  pub async fn cache_stats(&self) -> Option<(usize, usize)> {
+    let mut stats = None;
+    for store in self.cache_stores() {
+      let store = store.read().await;
+      let (files, bytes) = stats.get_or_insert((0, 0));
+      *files += store.len();
+      *bytes += store.bytes();
+    }
+    stats
+  }
+
+  /// 重新读取所有挂载目录的缓存，返回缓存的文件总数，任意一个失败时返回 None
+  pub async fn refresh_cache(&self) -> Option<usize> {
+    let mut files = 0;
+    for store in self.cache_stores() {
+      files += refresh_cache_store(store).await?;
+    }
+    Some(files)
+  }
+}
+
+impl Default for ConfigBuilder {
+  fn default() -> Self {
+    ConfigBuilder {
+      root_dir: None,
+      index: "index.html".to_string(),
+      html5: false,
+      base_href: None,
+      gzip: false,
+      delay: 0,
+      faults: Vec::new(),
+      access_log: true,
+      access_log_format: AccessLogFormat::Default,
+      mime: MimeTable::default(),
+      cache_in_memory: false,
+      regex_immutable: None,
+      health_path: None,
+      ready_path: None,
+      mounts: Vec::new(),
+      vhosts: Vec::new(),
+      auth: None,
+      signer: None,
+      access: AccessControl::default(),
+      rate_limit: None,
+      max_requests: None,
+    }
+  }
+}
+
+/// 构造 `Config`，用于在自己的 tide 服务中嵌入静态文件服务
+pub struct ConfigBuilder {
+  root_dir: Option<PathBuf>,
+  index: String,
+  html5: bool,
+  base_href: Option<String>,
+  gzip: bool,
+  delay: u64,
+  faults: Vec<FaultRule>,
+  access_log: bool,
+  access_log_format: AccessLogFormat,
+  mime: MimeTable,
+  cache_in_memory: bool,
+  regex_immutable: Option<Regex>,
+  health_path: Option<String>,
+  ready_path: Option<String>,
+  mounts: Vec<MountSpec>,
+  vhosts: Vec<VhostSpec>,
+  auth: Option<BasicAuth>,
+  signer: Option<UrlSigner>,
+  access: AccessControl,
+  rate_limit: Option<(f64, u32)>,
+  max_requests: Option<usize>,
+}
+
+impl ConfigBuilder {
+  /// 对应命令行参数，相对路径基于 cwd
+  pub(crate) fn from_args(args: &args::Args, cwd: &Path) -> io::Result<Self> {
+    let mime_file = args
+      .mime_file
+      .as_ref()
+      .map(|file| resolve_path(cwd, Path::new(file)));
+    let mut builder = ConfigBuilder::default()
+      .index(&args.index)
+      .html5(args.html5)
+      .gzip(args.gzip)
+      .delay(args.delay)
+      .access_log(!args.no_access)
+      .access_log_format(args.access_log_format.clone())
+      .mime(MimeTable::load(&args.mime, mime_file.as_deref())?)
+      .cache_in_memory(args.cache_in_memory);
+    for rule in args.fault.iter() {
+      builder = builder.fault(rule.clone());
+    }
+    builder.base_href = args.base_href.clone();
+    builder.regex_immutable = args.regex_immutable.clone();
+    builder.health_path = args.health_path.clone();
+    builder.ready_path = args.ready_path.clone();
+    builder.root_dir = args
+      .serve_path
+      .as_ref()
+      .map(|path| resolve_path(cwd, Path::new(path)));
+    let resolve = |spec: &MountSpec| MountSpec {
+      dir: resolve_path(cwd, Path::new(&spec.dir))
+        .to_string_lossy()
+        .into_owned(),
+      ..spec.clone()
+    };
+    for spec in args.mount.iter() {
+      builder = builder.mount(resolve(spec));
+    }
+    for spec in args.vhost.iter() {
+      builder = builder.vhost(VhostSpec {
+        host: spec.host.clone(),
+        mount: resolve(&spec.mount),
+      });
+    }
+    if !args.basic_auth.is_empty() || args.htpasswd.is_some() {
+      let htpasswd = args
+        .htpasswd
+        .as_ref()
+        .map(|file| resolve_path(cwd, Path::new(file)));
+      builder = builder.basic_auth(BasicAuth::new(
+        &args.auth_realm,
+        args.auth_path.clone(),
+        args.basic_auth.clone(),
+        htpasswd,
+      )?);
+    }
+    if let Some(secret) = &args.sign_secret {
+      builder = builder.url_signer(UrlSigner::new(secret, args.sign_path.clone()));
+    }
+    builder = builder.access_control(AccessControl {
+      allow: args.allow.clone(),
+      deny: args.deny.clone(),
+      trusted_proxies: args.trusted_proxy.clone(),
+    });
+    if let Some(rate) = args.rate_limit {
+      let burst = args.rate_burst.unwrap_or(rate.ceil() as u32);
+      builder = builder.rate_limit(rate, burst);
+    }
+    if let Some(max) = args.max_requests {
+      builder = builder.max_requests(max.get());
+    }
+    Ok(builder)
+  }
+
+  /// html5 模式下找不到文件时返回的页面，相对 root_dir
+  pub fn index(mut self, index: &str) -> Self {
+    self.index = index.to_string();
+    self
+  }
+
+  /// history api fallback，找不到文件时返回 index 页面
+  pub fn html5(mut self, html5: bool) -> Self {
+    self.html5 = html5;
+    self
+  }
+
+  /// 只服务该路径下的请求，如 "/app/"
+  pub fn base_href(mut self, base_href: &str) -> Self {
+    self.base_href = normalize_base_href(base_href);
+    self
+  }
+
+  pub fn gzip(mut self, gzip: bool) -> Self {
+    self.gzip = gzip;
+    self
+  }
+
+  /// 每个响应延迟的毫秒数，用于模拟慢速网络
+  pub fn delay(mut self, delay: u64) -> Self {
+    self.delay = delay;
+    self
+  }
+
+  /// 为匹配的请求注入延迟、限速或错误，可以多次调用，使用第一个匹配的规则
+  pub fn fault(mut self, rule: FaultRule) -> Self {
+    self.faults.push(rule);
+    self
+  }
+
+  pub fn access_log(mut self, enabled: bool) -> Self {
+    self.access_log = enabled;
+    self
+  }
+
+  pub fn access_log_format(mut self, format: AccessLogFormat) -> Self {
+    self.access_log_format = format;
+    self
+  }
+
+  pub fn mime(mut self, mime: MimeTable) -> Self {
+    self.mime = mime;
+    self
+  }
+
+  /// build 时把所有文件读入内存
+  pub fn cache_in_memory(mut self, cache: bool) -> Self {
+    self.cache_in_memory = cache;
+    self
+  }
+
+  /// 匹配的文件带上永久缓存的 cache-control，只对开启了缓存的目录有效
+  pub fn regex_immutable(mut self, regex: Regex) -> Self {
+    self.regex_immutable = Some(regex);
+    self
+  }
+
+  pub fn health_path(mut self, path: &str) -> Self {
+    self.health_path = Some(path.to_string());
+    self
+  }
+
+  /// `Config::ready` 置为 true 之后才返回 200
+  pub fn ready_path(mut self, path: &str) -> Self {
+    self.ready_path = Some(path.to_string());
+    self
+  }
+
+  /// 把 dir 挂载到 prefix 下，html5、cache_in_memory 等选项只对根目录有效，挂载目录使用自己的选项
+  pub fn mount(mut self, spec: MountSpec) -> Self {
+    self.mounts.push(spec);
+    self
+  }
+
+  /// Host 匹配时使用 spec 中的目录和选项，不再使用根目录和 mount 的目录
+  pub fn vhost(mut self, spec: VhostSpec) -> Self {
+    self.vhosts.push(spec);
+    self
+  }
+
+  /// 所有站点（包括虚拟主机）的请求都需要认证，探针路径除外
+  pub fn basic_auth(mut self, auth: BasicAuth) -> Self {
+    self.auth = Some(auth);
+    self
+  }
+
+  /// 需要签名的路径只接受有效且没有过期的签名链接，basic auth 仍然需要单独通过
+  pub fn url_signer(mut self, signer: UrlSigner) -> Self {
+    self.signer = Some(signer);
+    self
+  }
+
+  /// 按客户端 ip 拒绝请求，在探针和认证之前检查
+  pub fn access_control(mut self, access: AccessControl) -> Self {
+    self.access = access;
+    self
+  }
+
+  /// 每个客户端 ip 每秒最多 rate 个请求，最多连续 burst 个，超过时返回 429
+  pub fn rate_limit(mut self, rate: f64, burst: u32) -> Self {
+    self.rate_limit = Some((rate, burst));
+    self
+  }
+
+  /// 同时处理的请求超过 max 时返回 503
+  pub fn max_requests(mut self, max: usize) -> Self {
+    self.max_requests = Some(max);
+    self
+  }
+
+  /// cache_in_memory 时会读取所有文件，文件读取失败、挂载前缀或虚拟主机重复时返回错误
+  pub async fn build(self) -> io::Result<Config> {
+    let mut specs = self.mounts;
+    if let Some(root_dir) = &self.root_dir {
+      let mut spec = MountSpec::new(
+        self.base_href.as_deref().unwrap_or("/"),
+        &root_dir.to_string_lossy(),
+      );
+      spec.html5 = self.html5;
+      spec.cache = self.cache_in_memory;
+      // 和之前一样，根目录总是可以列出文件
+      spec.listing = true;
+      spec.index = self.index;
+      specs.push(spec);
+    }
+    let mounts = load_mounts(specs, self.regex_immutable.as_ref()).await?;
+    let mut vhosts = Vec::with_capacity(self.vhosts.len());
+    for spec in self.vhosts {
+      if vhosts
+        .iter()
+        .any(|vhost: &VirtualHost| vhost.host == spec.host)
+      {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          format!("virtual host {} is defined more than once", spec.host),
+        ));
+      }
+      vhosts.push(VirtualHost {
+        host: spec.host,
+        mounts: load_mounts(vec![spec.mount], self.regex_immutable.as_ref()).await?,
+      });
     }
+    // 精确匹配优先，通配符越长越优先
+    vhosts.sort_by_key(|vhost| vhost.host.priority());
+    Ok(Config {
+      delay: self.delay,
+      faults: self.faults,
+      should_log_access: AtomicBool::new(self.access_log),
+      access_log_format: self.access_log_format,
+      base_href: self.base_href,
+      mounts,
+      vhosts,
+      gzip: self.gzip,
+      mime: self.mime,
+      auth: self.auth,
+      signer: self.signer,
+      access: self.access,
+      rate_limit: self
+        .rate_limit
+        .map(|(rate, burst)| RateLimit::new(rate, burst)),
+      max_requests: self
+        .max_requests
+        .map(|max| Arc::new(ConcurrencyLimit::new(max))),
+      tls: None,
+      health_path: self.health_path,
+      ready_path: self.ready_path,
+      ready: AtomicBool::new(true),
+    })
   }
 }
 
@@ -0,0 +1,267 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_io::Timer;
+use async_std::io::BufReader;
+use fastrand::Rng;
+use futures::AsyncRead;
+use http_types::{Body, StatusCode};
+use regex::Regex;
+use tide::Response;
+
+use crate::util::parse_size;
+
+/// latency 和 jitter 的上限（6 小时），单位毫秒
+const MAX_FAULT_MS: u64 = 6 * 3600 * 1000;
+
+/// --fault 的参数，用于测试页面的加载状态，如 "latency=200-800,jitter=50,bandwidth=64K,error=503@0.1,path=^/api/"。
+/// path 是匹配请求路径的正则，可能带逗号，所以必须放在最后，不指定时匹配所有路径。
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+  /// 毫秒，在范围内均匀随机
+  pub latency: Option<(u64, u64)>,
+  /// 毫秒，在 latency 的基础上随机增减
+  pub jitter: u64,
+  /// 响应体每秒最多发送的字节数
+  pub bandwidth: Option<u64>,
+  /// (状态码, 概率)
+  pub errors: Vec<(StatusCode, f64)>,
+  pub path: Option<Regex>,
+}
+
+impl FaultRule {
+  pub fn matches(&self, path: &str) -> bool {
+    self.path.as_ref().is_none_or(|regex| regex.is_match(path))
+  }
+
+  /// 本次请求额外的延迟
+  pub fn latency(&self, rng: &mut Rng) -> Duration {
+    let (min, max) = self.latency.unwrap_or((0, 0));
+    let ms = rng
+      .u64(min..=max)
+      .saturating_add(rng.u64(0..=self.jitter.saturating_mul(2)));
+    Duration::from_millis(ms.saturating_sub(self.jitter))
+  }
+
+  /// 按概率决定本次请求是否返回错误，多个错误的概率相加
+  pub fn error(&self, rng: &mut Rng) -> Option<StatusCode> {
+    let roll = rng.f64();
+    let mut total = 0.0;
+    self.errors.iter().find_map(|(status, probability)| {
+      total += probability;
+      (roll < total).then_some(*status)
+    })
+  }
+}
+
+impl FromStr for FaultRule {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (options, path) = match s.strip_prefix("path=") {
+      Some(path) => ("", Some(path)),
+      None => match s.split_once(",path=") {
+        Some((options, path)) => (options, Some(path)),
+        None => (s, None),
+      },
+    };
+    let mut rule = FaultRule {
+      latency: None,
+      jitter: 0,
+      bandwidth: None,
+      errors: Vec::new(),
+      path: path
+        .map(Regex::new)
+        .transpose()
+        .map_err(|err| format!("invalid path regex: {}", err))?,
+    };
+    for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+      let invalid = || format!("invalid fault option \"{}\"", option);
+      let parse_ms = |ms: &str| {
+        ms.trim()
+          .parse::<u64>()
+          .ok()
+          .filter(|ms| *ms <= MAX_FAULT_MS)
+          .ok_or_else(invalid)
+      };
+      match option.split_once('=') {
+        Some(("latency", ms)) => {
+          let (min, max) = ms.split_once('-').unwrap_or((ms, ms));
+          let (min, max) = (parse_ms(min)?, parse_ms(max)?);
+          if min > max {
+            return Err(invalid());
+          }
+          rule.latency = Some((min, max));
+        }
+        Some(("jitter", ms)) => rule.jitter = parse_ms(ms)?,
+        Some(("bandwidth", size)) => {
+          let size = parse_size(size)?;
+          if size == 0 {
+            return Err(invalid());
+          }
+          rule.bandwidth = Some(size);
+        }
+        Some(("error", error)) => {
+          let (status, probability) = error.split_once('@').unwrap_or((error, "1"));
+          let status = status
+            .trim()
+            .parse::<u16>()
+            .ok()
+            .and_then(|status| StatusCode::try_from(status).ok())
+            .filter(|status| status.is_client_error() || status.is_server_error())
+            .ok_or_else(invalid)?;
+          let probability = probability
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|p| (0.0..=1.0).contains(p))
+            .ok_or_else(invalid)?;
+          rule.errors.push((status, probability));
+        }
+        _ => {
+          return Err(format!(
+            "unknown fault option \"{}\", expect latency=MS[-MS], jitter=MS, bandwidth=SIZE, error=STATUS[@PROBABILITY] or path=REGEX",
+            option
+          ))
+        }
+      }
+    }
+    if rule.errors.iter().map(|(_, p)| p).sum::<f64>() > 1.0 {
+      return Err(format!(
+        "error probabilities of fault \"{}\" add up over 1",
+        s
+      ));
+    }
+    Ok(rule)
+  }
+}
+
+/// 限制响应体的发送速度
+pub fn throttle(res: &mut Response, bytes_per_sec: u64) {
+  let body = res.take_body();
+  let mime = body.mime().clone();
+  let len = body.len();
+  let mut throttled = Body::from_reader(
+    BufReader::new(Throttled {
+      inner: body,
+      bytes_per_sec,
+      start: None,
+      sent: 0,
+      timer: None,
+    }),
+    len,
+  );
+  throttled.set_mime(mime);
+  res.set_body(throttled);
+}
+
+/// 每次最多读出 1/10 秒的数据，读得太快时等到按速度应该发送完的时间
+struct Throttled<R> {
+  inner: R,
+  bytes_per_sec: u64,
+  start: Option<Instant>,
+  sent: u64,
+  timer: Option<Timer>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+  ) -> Poll<std::io::Result<usize>> {
+    let this = self.get_mut();
+    loop {
+      if let Some(timer) = &mut this.timer {
+        ready!(Pin::new(timer).poll(cx));
+        this.timer = None;
+      }
+      let start = *this.start.get_or_insert_with(Instant::now);
+      let due = start + Duration::from_secs_f64(this.sent as f64 / this.bytes_per_sec as f64);
+      if due > Instant::now() {
+        this.timer = Some(Timer::at(due));
+        continue;
+      }
+      let chunk = ((this.bytes_per_sec / 10) as usize).max(1).min(buf.len());
+      let size = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..chunk]))?;
+      this.sent += size as u64;
+      return Poll::Ready(Ok(size));
+    }
+  }
+}
+
+#[test]
+fn test_fault_rule() {
+  let rule: FaultRule =
+    "latency=200-800,jitter=50,bandwidth=64K,error=503@0.1,error=404@0.2,path=^/api/(a|b),c"
+      .parse()
+      .unwrap();
+  assert_eq!(Some((200, 800)), rule.latency);
+  assert_eq!(50, rule.jitter);
+  assert_eq!(Some(64 * 1024), rule.bandwidth);
+  assert_eq!(
+    vec![
+      (StatusCode::ServiceUnavailable, 0.1),
+      (StatusCode::NotFound, 0.2)
+    ],
+    rule.errors
+  );
+  assert!(rule.matches("/api/a,c"));
+  assert!(!rule.matches("/app.js"));
+
+  let mut rng = Rng::with_seed(7);
+  for _ in 0..100 {
+    let latency = rule.latency(&mut rng);
+    assert!(latency >= Duration::from_millis(150) && latency <= Duration::from_millis(850));
+  }
+  let errors = (0..10000).filter_map(|_| rule.error(&mut rng)).count();
+  assert!((2500..3500).contains(&errors));
+
+  let rule: FaultRule = "latency=100,error=500".parse().unwrap();
+  assert!(rule.matches("/anything"));
+  assert_eq!(Duration::from_millis(100), rule.latency(&mut rng));
+  assert_eq!(Some(StatusCode::InternalServerError), rule.error(&mut rng));
+  let rule: FaultRule = "path=\\.js$".parse().unwrap();
+  assert!(rule.matches("/app.js") && !rule.matches("/"));
+  assert_eq!(None, rule.error(&mut rng));
+
+  let rule: FaultRule = "latency=0,jitter=50".parse().unwrap();
+  for _ in 0..100 {
+    assert!(rule.latency(&mut rng) <= Duration::from_millis(50));
+  }
+  let max = format!("latency={0},jitter={0}", MAX_FAULT_MS);
+  let rule: FaultRule = max.parse().unwrap();
+  assert!(rule.latency(&mut rng) <= Duration::from_millis(MAX_FAULT_MS * 2));
+
+  assert!("latency=800-200".parse::<FaultRule>().is_err());
+  assert!(format!("latency={}", MAX_FAULT_MS + 1)
+    .parse::<FaultRule>()
+    .is_err());
+  assert!("jitter=18446744073709551615".parse::<FaultRule>().is_err());
+  assert!("error=200".parse::<FaultRule>().is_err());
+  assert!("error=503@1.5".parse::<FaultRule>().is_err());
+  assert!("error=503@0.6,error=500@0.6".parse::<FaultRule>().is_err());
+  assert!("bandwidth=0".parse::<FaultRule>().is_err());
+  assert!("timeout=1".parse::<FaultRule>().is_err());
+  assert!("path=(".parse::<FaultRule>().is_err());
+}
+
+#[async_std::test]
+async fn test_throttle() {
+  use async_std::io::ReadExt;
+  let mut res = Response::builder(200)
+    .body(vec![1u8; 300])
+    .content_type("application/wasm")
+    .build();
+  throttle(&mut res, 1000);
+  assert_eq!(Some(300), res.len());
+  assert_eq!("application/wasm", res.content_type().unwrap().essence());
+  let start = Instant::now();
+  let mut body = Vec::new();
+  res.take_body().read_to_end(&mut body).await.unwrap();
+  assert_eq!(vec![1u8; 300], body);
+  assert!(start.elapsed() >= Duration::from_millis(200));
+}
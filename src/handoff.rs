@@ -0,0 +1,226 @@
+use std::fs;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{self, Command};
+use std::sync::Mutex;
+
+use async_std::net::TcpListener;
+use async_std::os::unix::net::UnixListener;
+use log::info;
+
+use crate::listener::{ListenAddr, Listener};
+
+/// systemd socket activation 使用的环境变量，继承的 fd 从 3 开始连续编号
+const LISTEN_FDS: &str = "LISTEN_FDS";
+const LISTEN_PID: &str = "LISTEN_PID";
+/// SIGUSR2 升级时传给新进程的监听 fd 列表，如 "3,7"
+const LIGHTSTATIC_LISTEN_FDS: &str = "LIGHTSTATIC_LISTEN_FDS";
+/// SIGUSR2 升级时传给新进程的旧进程 pid，新进程就绪后通知旧进程退出
+const LIGHTSTATIC_UPGRADE_FROM: &str = "LIGHTSTATIC_UPGRADE_FROM";
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// 继承自 systemd 或旧进程、还没有被使用的监听 socket
+static INHERITED: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+/// 当前进程所有的监听 socket，升级时全部交给新进程
+static LISTENING: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+/// 读取继承的监听 socket，返回旧进程的 pid（SIGUSR2 升级时）。需要在绑定任何端口之前调用。
+pub fn collect_inherited() -> Option<u32> {
+  let mut fds = Vec::new();
+  if let Ok(list) = std::env::var(LIGHTSTATIC_LISTEN_FDS) {
+    fds.extend(list.split(',').filter_map(|fd| fd.parse::<RawFd>().ok()));
+  } else if std::env::var(LISTEN_PID).ok() == Some(process::id().to_string()) {
+    let count = std::env::var(LISTEN_FDS)
+      .ok()
+      .and_then(|n| n.parse::<RawFd>().ok())
+      .unwrap_or(0);
+    fds.extend(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count);
+  }
+  let upgrade_from = std::env::var(LIGHTSTATIC_UPGRADE_FROM)
+    .ok()
+    .and_then(|pid| pid.parse().ok());
+  // 避免传递给之后启动的子进程
+  for key in [
+    LISTEN_FDS,
+    LISTEN_PID,
+    "LISTEN_FDNAMES",
+    LIGHTSTATIC_LISTEN_FDS,
+    LIGHTSTATIC_UPGRADE_FROM,
+  ] {
+    std::env::remove_var(key);
+  }
+  for fd in fds.iter() {
+    set_cloexec(*fd, true);
+  }
+  if !fds.is_empty() {
+    info!("Inherited {} listening sockets", fds.len());
+  }
+  *INHERITED.lock().unwrap() = fds;
+  upgrade_from
+}
+
+fn take_inherited(matches: impl Fn(RawFd) -> bool) -> Option<RawFd> {
+  let mut inherited = INHERITED.lock().unwrap();
+  let idx = inherited.iter().position(|fd| matches(*fd))?;
+  Some(inherited.remove(idx))
+}
+
+fn tcp_addr(fd: RawFd) -> Option<SocketAddr> {
+  // 只借用 fd 查询地址，不能 drop 关闭它
+  let listener = ManuallyDrop::new(unsafe { std::net::TcpListener::from_raw_fd(fd) });
+  listener.local_addr().ok()
+}
+
+fn register(fd: RawFd) {
+  LISTENING.lock().unwrap().push(fd);
+}
+
+/// 优先使用继承的、地址相同的 socket，否则绑定新端口。
+/// any_inherited 为 true 时，没有地址相同的也使用第一个继承的 TCP socket（systemd 的 socket 端口可能和参数不一致）。
+pub async fn bind_tcp(addr: impl ToSocketAddrs, any_inherited: bool) -> io::Result<TcpListener> {
+  let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+  let inherited = take_inherited(|fd| tcp_addr(fd).is_some_and(|addr| addrs.contains(&addr)))
+    .or_else(|| {
+      if any_inherited {
+        take_inherited(|fd| tcp_addr(fd).is_some())
+      } else {
+        None
+      }
+    });
+  let listener = match inherited {
+    Some(fd) => unsafe { TcpListener::from_raw_fd(fd) },
+    None => TcpListener::bind(&addrs[..]).await?,
+  };
+  register(listener.as_raw_fd());
+  Ok(listener)
+}
+
+/// 继承的 TCP socket 中是否有可用于主服务的，有则不再自动选择空闲端口
+pub fn has_inherited_tcp() -> bool {
+  INHERITED
+    .lock()
+    .unwrap()
+    .iter()
+    .any(|fd| tcp_addr(*fd).is_some())
+}
+
+/// 优先使用继承的同路径 unix socket，否则清理遗留的 socket 文件后绑定，并设置文件权限。
+pub async fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+  let inherited = take_inherited(|fd| {
+    let listener = ManuallyDrop::new(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) });
+    listener
+      .local_addr()
+      .is_ok_and(|addr| addr.as_pathname() == Some(path))
+  });
+  let listener = match inherited {
+    Some(fd) => unsafe { UnixListener::from_raw_fd(fd) },
+    None => {
+      // 上次异常退出遗留的 socket 文件会导致 bind 失败，普通文件和还有进程在监听的 socket 则不删除
+      if fs::symlink_metadata(path).is_ok_and(|stat| stat.file_type().is_socket()) {
+        match UnixStream::connect(path) {
+          Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+          _ => {
+            return Err(io::Error::new(
+              io::ErrorKind::AddrInUse,
+              format!("{} is in use by another process", path.display()),
+            ))
+          }
+        }
+      }
+      // 通过 umask 让 socket 文件创建时就是目标权限，bind 之后再 chmod 会有一段时间使用默认权限
+      let umask = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+      let listener = UnixListener::bind(path).await;
+      unsafe { libc::umask(umask) };
+      listener?
+    }
+  };
+  register(listener.as_raw_fd());
+  Ok(listener)
+}
+
+pub async fn bind(addr: &ListenAddr, unix_mode: u32) -> io::Result<Listener> {
+  Ok(match addr {
+    ListenAddr::Tcp(addr) => bind_tcp(*addr, false).await?.into(),
+    ListenAddr::Unix(path) => bind_unix(path, unix_mode).await?.into(),
+  })
+}
+
+/// 以相同的参数启动新进程并把所有监听 socket 交给它，新进程就绪后会通知当前进程优雅退出。
+pub fn upgrade() -> io::Result<u32> {
+  let fds = LISTENING.lock().unwrap().clone();
+  // 二进制文件被替换后 current_exe() 指向已删除的旧文件，所以和 nginx 一样使用 argv[0]
+  let mut args = std::env::args_os();
+  let program = args.next().unwrap_or_else(|| "lightstatic".into());
+  let mut command = Command::new(program);
+  command
+    .args(args)
+    .env(
+      LIGHTSTATIC_LISTEN_FDS,
+      fds
+        .iter()
+        .map(|fd| fd.to_string())
+        .collect::<Vec<_>>()
+        .join(","),
+    )
+    .env(LIGHTSTATIC_UPGRADE_FROM, process::id().to_string());
+  unsafe {
+    command.pre_exec(move || {
+      for fd in fds.iter() {
+        if !set_cloexec(*fd, false) {
+          return Err(io::Error::last_os_error());
+        }
+      }
+      Ok(())
+    });
+  }
+  Ok(command.spawn()?.id())
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> bool {
+  unsafe {
+    let flags = libc::fcntl(fd, libc::F_GETFD);
+    if flags < 0 {
+      return false;
+    }
+    let flags = if cloexec {
+      flags | libc::FD_CLOEXEC
+    } else {
+      flags & !libc::FD_CLOEXEC
+    };
+    libc::fcntl(fd, libc::F_SETFD, flags) == 0
+  }
+}
+
+#[async_std::test]
+async fn test_bind_unix() {
+  use std::os::unix::fs::PermissionsExt;
+  let path = std::env::temp_dir().join(format!("lightstatic-bind-{}.sock", process::id()));
+  let _ = fs::remove_file(&path);
+  let listener = bind_unix(&path, 0o600).await.unwrap();
+  assert_eq!(
+    0o600,
+    fs::metadata(&path).unwrap().permissions().mode() & 0o777
+  );
+  // 还在监听的 socket 不会被抢走
+  let err = bind_unix(&path, 0o600).await.unwrap_err();
+  assert_eq!(io::ErrorKind::AddrInUse, err.kind());
+  // 进程退出后遗留的 socket 文件会被清理
+  drop(listener);
+  assert!(path.exists());
+  let listener = bind_unix(&path, 0o660).await.unwrap();
+  assert_eq!(
+    0o660,
+    fs::metadata(&path).unwrap().permissions().mode() & 0o777
+  );
+  drop(listener);
+  fs::remove_file(&path).unwrap();
+  fs::write(&path, "").unwrap();
+  assert!(bind_unix(&path, 0o600).await.is_err());
+  fs::remove_file(&path).unwrap();
+}
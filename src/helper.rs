@@ -5,16 +5,20 @@ use async_std::{
   path::Path,
   stream::StreamExt,
 };
-use http_types::{mime::*, Mime};
+use http_types::Mime;
 use tide::{Body, Request, Response};
 
 use crate::config::AppConfig;
+use crate::mime::is_dir_mime_file;
 
 pub async fn send_dir(dir: &Path, path: &str) -> tide::Result<Response> {
   let mut list = dir.read_dir().await?;
   let mut cnt = String::new();
   while let Some(item) = list.next().await {
     let item = item?.path();
+    if is_dir_mime_file(&item) {
+      continue;
+    }
     let file_name = item
       .file_name()
       .and_then(|s| s.to_str())
@@ -55,35 +59,12 @@ li {{ padding: 6px; }}
   )
 }
 
-pub fn get_mime(file: &Path) -> Mime {
-  get_mime_from_ext(file.extension().and_then(|p| p.to_str()))
-}
-
-pub fn get_mime_from_ext(ext: Option<&str>) -> Mime {
-  let Some(ext) = ext else {
-    return BYTE_STREAM;
-  };
-  match ext {
-    "html" => HTML,
-    "js" | "mjs" | "jsonp" | "ts" | "tsx" | "jsx" => JAVASCRIPT,
-    "css" => CSS,
-    "jpeg" | "jpg" => JPEG,
-    "icon" | "ico" => ICO,
-    "png" => PNG,
-    "wasm" => WASM,
-    "json" => JSON,
-    "svg" => SVG,
-    "xml" => XML,
-    "txt" => PLAIN,
-    _ => BYTE_STREAM
-  }
-}
-
 pub async fn send_file(
   filepath: &Path,
   stat: &std::fs::Metadata,
   mtime: &str,
   gzip: bool,
+  mime: Mime,
 ) -> tide::Result<Response> {
   let file = fs::File::open(filepath).await?;
   let mut body;
@@ -94,7 +75,7 @@ pub async fn send_file(
   } else {
     body = Body::from_reader(BufReader::new(file), Some(stat.len() as usize));
   }
-  body.set_mime(get_mime(filepath));
+  body.set_mime(mime);
   Ok(res.body(body).build())
 }
 
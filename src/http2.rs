@@ -0,0 +1,165 @@
+use async_std::io::ErrorKind;
+use async_std::net::SocketAddr;
+use async_std::task;
+use bytes::Bytes;
+use futures::future::poll_fn;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http_types::{Method, Url, Version};
+use log::warn;
+use tide::Server;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+use crate::shutdown::{until_shutdown, InFlight};
+
+/// HTTP/2 客户端连接的前 24 字节（connection preface），h2c 通过它识别。
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// 发送响应时单个 DATA 帧的最大长度
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// 处理一个 HTTP/2 连接，每个 stream 上的请求都交给 tide 的 app 处理，和 HTTP/1.1 共用同一套逻辑。
+pub async fn accept_h2<State, IO>(
+  app: Server<State>,
+  io: IO,
+  local_addr: Option<SocketAddr>,
+  peer_addr: Option<SocketAddr>,
+  tls: bool,
+) -> http_types::Result<()>
+where
+  State: Clone + Send + Sync + 'static,
+  IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+  let mut conn = h2::server::handshake(io.compat()).await?;
+  let mut going_away = false;
+  loop {
+    let next = if going_away {
+      conn.accept().await
+    } else {
+      match until_shutdown(conn.accept()).await {
+        Some(next) => next,
+        None => {
+          // 发送 GOAWAY，继续驱动连接直到已有的 stream 全部完成
+          conn.graceful_shutdown();
+          going_away = true;
+          continue;
+        }
+      }
+    };
+    let Some(stream) = next else {
+      break;
+    };
+    let (req, respond) = match stream {
+      Ok(stream) => stream,
+      // 客户端直接断开 TCP 连接是 HTTP/2 下关闭连接的常见方式，不作为错误
+      Err(err) if is_disconnect(&err) => return Ok(()),
+      Err(err) => return Err(err.into()),
+    };
+    let app = app.clone();
+    let in_flight = InFlight::start();
+    task::spawn(async move {
+      let _in_flight = in_flight;
+      if let Err(err) = handle_stream(app, req, respond, local_addr, peer_addr, tls).await {
+        warn!("http2 stream error: {}", err);
+      }
+    });
+  }
+  Ok(())
+}
+
+fn is_disconnect(err: &h2::Error) -> bool {
+  matches!(
+    err.get_io().map(|e| e.kind()),
+    Some(ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof)
+  )
+}
+
+async fn handle_stream<State: Clone + Send + Sync + 'static>(
+  app: Server<State>,
+  req: http::Request<RecvStream>,
+  mut respond: SendResponse<Bytes>,
+  local_addr: Option<SocketAddr>,
+  peer_addr: Option<SocketAddr>,
+  tls: bool,
+) -> http_types::Result<()> {
+  let (parts, mut body) = req.into_parts();
+  let scheme = parts
+    .uri
+    .scheme_str()
+    .unwrap_or(if tls { "https" } else { "http" });
+  let authority = parts
+    .uri
+    .authority()
+    .map(|a| a.as_str())
+    .or_else(|| parts.headers.get("host").and_then(|h| h.to_str().ok()))
+    .unwrap_or("localhost");
+  let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+  let url = Url::parse(&format!("{}://{}{}", scheme, authority, path))?;
+  let method: Method = parts.method.as_str().parse()?;
+
+  let mut req = http_types::Request::new(method, url);
+  for (name, value) in parts.headers.iter() {
+    if let Ok(value) = value.to_str() {
+      req.append_header(name.as_str(), value);
+    }
+  }
+  // 静态文件服务基本不会有请求体，这里直接完整读取
+  let mut req_body = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk?;
+    let _ = body.flow_control().release_capacity(chunk.len());
+    req_body.extend_from_slice(&chunk);
+  }
+  if !req_body.is_empty() {
+    req.set_body(req_body);
+  }
+  req.set_version(Some(Version::Http2_0));
+  req.set_local_addr(local_addr);
+  req.set_peer_addr(peer_addr);
+
+  let mut res: http_types::Response = app.respond(req).await?;
+  let mut head = http::Response::builder().status(u16::from(res.status()));
+  for (name, values) in res.iter() {
+    // HTTP/2 禁止逐跳（hop-by-hop）头部
+    if matches!(
+      name.as_str(),
+      "connection" | "keep-alive" | "transfer-encoding" | "upgrade"
+    ) {
+      continue;
+    }
+    for value in values.iter() {
+      head = head.header(name.as_str(), value.as_str());
+    }
+  }
+  let body_len = res.len();
+  if let Some(len) = body_len {
+    head = head.header("content-length", len);
+  }
+  // HEAD 只返回头部，content-length 仍然是 GET 时的长度
+  let is_head = parts.method == http::Method::HEAD;
+  let end_of_stream = is_head || body_len == Some(0) || res.is_empty() == Some(true);
+  let mut send = respond.send_response(head.body(())?, end_of_stream)?;
+  if end_of_stream {
+    return Ok(());
+  }
+
+  let mut res_body = res.take_body();
+  let mut buf = vec![0u8; CHUNK_SIZE];
+  loop {
+    let size = res_body.read(&mut buf).await?;
+    if size == 0 {
+      send.send_data(Bytes::new(), true)?;
+      return Ok(());
+    }
+    let mut data = Bytes::copy_from_slice(&buf[..size]);
+    // 按流控窗口分段发送
+    while !data.is_empty() {
+      send.reserve_capacity(data.len());
+      let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+        Some(capacity) => capacity?,
+        None => return Err(http_types::Error::from_str(500, "http2 stream closed")),
+      };
+      send.send_data(data.split_to(capacity.min(data.len())), false)?;
+    }
+  }
+}
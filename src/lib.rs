@@ -0,0 +1,86 @@
+//! 轻量的静态文件服务器，也可以作为库嵌入到自己的 tide / async-std 服务中。
+//!
+//! ```no_run
+//! use lightstatic::{static_files, Config};
+//! use std::sync::Arc;
+//!
+//! #[async_std::main]
+//! async fn main() -> tide::Result<()> {
+//!   let config = Config::builder("./dist")
+//!     .html5(true)
+//!     .gzip(true)
+//!     .cache_in_memory(true)
+//!     .build()
+//!     .await?;
+//!   let mut app = tide::new();
+//!   app.at("/api").get(|_| async { Ok("api") });
+//!   app.at("/").nest(static_files(Arc::new(config)));
+//!   app.listen("127.0.0.1:8080").await?;
+//!   Ok(())
+//! }
+//! ```
+//!
+//! 需要和命令行完全相同的行为（日志、信号、pid 文件、TLS 等）时使用 [`serve`]。
+
+mod access;
+mod admin;
+mod args;
+mod auth;
+mod config;
+mod fault;
+mod handoff;
+mod helper;
+mod http2;
+mod limit;
+mod listener;
+mod log_file;
+mod log_target;
+mod logger;
+mod metrics;
+mod mime;
+mod mount;
+mod pid;
+mod server;
+mod server_core;
+mod shutdown;
+mod sign;
+mod store;
+mod tls;
+mod util;
+mod vhost;
+
+use async_std::path::PathBuf;
+
+pub use crate::access::{AccessControl, IpRule};
+pub use crate::args::{check_args, get_args, Args, Command};
+pub use crate::auth::{BasicAuth, BasicAuthUser};
+pub use crate::config::{AppConfig, Config, ConfigBuilder};
+pub use crate::fault::FaultRule;
+pub use crate::logger::{AccessLogFormat, AccessLogTemplate};
+pub use crate::mime::{MimeOverride, MimeTable};
+pub use crate::mount::{Mount, MountSpec};
+pub use crate::server::static_files;
+pub use crate::server_core::handle_request;
+pub use crate::sign::UrlSigner;
+pub use crate::store::{purge_cache_file, refresh_cache_store, FileCacheStore};
+pub use crate::vhost::{HostPattern, VhostSpec, VirtualHost};
+
+use crate::logger::initialize_log;
+use crate::pid::handle_arg_signal;
+use crate::sign::handle_arg_sign;
+
+/// 按命令行参数启动完整的服务，直到收到退出信号并处理完请求后返回。
+/// 参数中有 --signal 时只向运行中的实例发送信号，`sign` 子命令只输出签名后的链接。
+pub async fn serve(args: Args) -> tide::Result<()> {
+  if args.command.is_some() {
+    handle_arg_sign(&args);
+    return Ok(());
+  }
+  let cwd = PathBuf::from(std::env::current_dir()?);
+  initialize_log(&args, &cwd);
+  if let Some(action) = &args.signal {
+    handle_arg_signal(action, &args);
+    return Ok(());
+  }
+  server::bootstrap(args, cwd).await
+}
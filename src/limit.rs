@@ -0,0 +1,231 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+
+/// 客户端数超过该值时清理已经回满的令牌桶
+const SWEEP_SIZE: usize = 10_000;
+/// 两次清理的最小间隔，避免新 ip 每次都遍历所有令牌桶
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// 超过该值时（大量不同的 ip 同时请求）淘汰一半令牌桶
+const MAX_BUCKETS: usize = 100_000;
+/// --rate-limit 的最小值，此时最多等待 1000 秒
+pub const MIN_RATE: f64 = 0.001;
+/// 需要等待的时间的上限，RateLimit::new 传入很小的 rate 时也不会溢出
+const MAX_WAIT: Duration = Duration::from_secs(86400);
+
+/// 每个客户端 ip 一个令牌桶，按 rate 每秒回复令牌，最多攒 burst 个。
+/// ipv6 客户端通常拥有整个 /64，按 /64 限速，避免换个地址就绕过限制。
+pub struct RateLimit {
+  rate: f64,
+  burst: f64,
+  buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+  /// ip => (剩余令牌, 上次更新的时间)
+  map: AHashMap<IpAddr, (f64, Instant)>,
+  last_sweep: Instant,
+}
+
+impl RateLimit {
+  pub fn new(rate: f64, burst: u32) -> Self {
+    RateLimit {
+      rate,
+      burst: burst.max(1) as f64,
+      buckets: Mutex::new(Buckets {
+        map: AHashMap::new(),
+        last_sweep: Instant::now(),
+      }),
+    }
+  }
+
+  /// 消耗一个令牌，没有令牌时返回需要等待的时间
+  pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+    self.check_at(ip, Instant::now())
+  }
+
+  fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+    let key = match ip.to_canonical() {
+      IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !(u64::MAX as u128)).into()),
+      ip => ip,
+    };
+    let mut buckets = self.buckets.lock().unwrap();
+    if buckets.map.len() >= SWEEP_SIZE && !buckets.map.contains_key(&key) {
+      if now.saturating_duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+        buckets
+          .map
+          .retain(|_, (tokens, last)| self.refill(*tokens, *last, now) < self.burst);
+        buckets.last_sweep = now;
+      }
+      if buckets.map.len() >= MAX_BUCKETS {
+        self.evict(&mut buckets.map, now);
+      }
+    }
+    let (tokens, last) = buckets.map.entry(key).or_insert((self.burst, now));
+    *tokens = self.refill(*tokens, *last, now);
+    *last = now;
+    if *tokens >= 1.0 {
+      *tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(
+        Duration::try_from_secs_f64((1.0 - *tokens) / self.rate)
+          .map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT)),
+      )
+    }
+  }
+
+  /// 淘汰令牌最多、其次最久没有请求的一半。正在被限速的客户端令牌最少，不会因为大量新 ip 而被重置
+  fn evict(&self, map: &mut AHashMap<IpAddr, (f64, Instant)>, now: Instant) {
+    let order = |a: &(f64, Instant), b: &(f64, Instant)| a.0.total_cmp(&b.0).then(b.1.cmp(&a.1));
+    let mut buckets: Vec<(f64, Instant)> = map
+      .values()
+      .map(|(tokens, last)| (self.refill(*tokens, *last, now), *last))
+      .collect();
+    let mid = buckets.len() / 2;
+    let threshold = *buckets.select_nth_unstable_by(mid, order).1;
+    map.retain(|_, (tokens, last)| {
+      order(&(self.refill(*tokens, *last, now), *last), &threshold).is_lt()
+    });
+  }
+
+  fn refill(&self, tokens: f64, last: Instant, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(last).as_secs_f64();
+    (tokens + elapsed * self.rate).min(self.burst)
+  }
+}
+
+/// 并发数上限，用于 --max-connections 和 --max-requests
+pub struct ConcurrencyLimit {
+  max: usize,
+  current: AtomicUsize,
+}
+
+impl ConcurrencyLimit {
+  pub fn new(max: usize) -> Self {
+    ConcurrencyLimit {
+      max,
+      current: AtomicUsize::new(0),
+    }
+  }
+
+  /// 已达上限时返回 None，否则返回的 Permit drop 前占用一个名额
+  pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+    self
+      .current
+      .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+        (current < self.max).then_some(current + 1)
+      })
+      .ok()?;
+    Some(Permit(self.clone()))
+  }
+}
+
+pub struct Permit(Arc<ConcurrencyLimit>);
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    self.0.current.fetch_sub(1, Ordering::AcqRel);
+  }
+}
+
+/// Retry-After 只支持整数秒，向上取整且至少为 1
+pub fn retry_after_secs(wait: Duration) -> u64 {
+  (wait.as_secs_f64().ceil() as u64).max(1)
+}
+
+#[test]
+fn test_rate_limit() {
+  let limit = RateLimit::new(2.0, 3);
+  let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+  let now = Instant::now();
+  for _ in 0..3 {
+    assert!(limit.check_at(ip("10.0.0.1"), now).is_ok());
+  }
+  let wait = limit.check_at(ip("10.0.0.1"), now).unwrap_err();
+  assert_eq!(Duration::from_millis(500), wait);
+  assert_eq!(1, retry_after_secs(wait));
+  assert!(limit.check_at(ip("::ffff:10.0.0.1"), now).is_err());
+  assert!(limit.check_at(ip("10.0.0.2"), now).is_ok());
+  assert!(limit
+    .check_at(ip("10.0.0.1"), now + Duration::from_millis(500))
+    .is_ok());
+  assert!(limit
+    .check_at(ip("10.0.0.1"), now + Duration::from_millis(600))
+    .is_err());
+
+  // 同一个 /64 共用令牌桶
+  for _ in 0..3 {
+    assert!(limit.check_at(ip("2001:db8::1"), now).is_ok());
+  }
+  assert!(limit.check_at(ip("2001:db8::2"), now).is_err());
+  assert!(limit.check_at(ip("2001:db8:0:1::1"), now).is_ok());
+
+  let slow = RateLimit::new(0.1, 1);
+  assert!(slow.check_at(ip("10.0.0.1"), now).is_ok());
+  assert_eq!(
+    10,
+    retry_after_secs(slow.check_at(ip("10.0.0.1"), now).unwrap_err())
+  );
+  let tiny = RateLimit::new(1e-30, 1);
+  assert!(tiny.check_at(ip("10.0.0.1"), now).is_ok());
+  assert_eq!(Err(MAX_WAIT), tiny.check_at(ip("10.0.0.1"), now));
+}
+
+#[test]
+fn test_rate_limit_sweep() {
+  let limit = RateLimit::new(1000.0, 1);
+  let len = || limit.buckets.lock().unwrap().map.len();
+  let fill = |start: u32, at: Instant| {
+    for i in start..start + SWEEP_SIZE as u32 {
+      assert!(limit.check_at(IpAddr::V4(i.into()), at).is_ok());
+    }
+  };
+  let start = Instant::now() + Duration::from_secs(2);
+  fill(0, start);
+  assert_eq!(SWEEP_SIZE, len());
+  // 已经回满的令牌桶被清理
+  let at = start + Duration::from_millis(10);
+  assert!(limit.check_at(IpAddr::V4(u32::MAX.into()), at).is_ok());
+  assert_eq!(1, len());
+  // 间隔内不会再次遍历
+  fill(1 << 20, at);
+  assert!(limit
+    .check_at(IpAddr::V4(1.into()), at + Duration::from_millis(10))
+    .is_ok());
+  assert_eq!(SWEEP_SIZE + 2, len());
+  assert!(limit
+    .check_at(IpAddr::V4(2.into()), at + SWEEP_INTERVAL)
+    .is_ok());
+  assert_eq!(1, len());
+}
+
+#[test]
+fn test_rate_limit_evict() {
+  let limit = RateLimit::new(1.0, 2);
+  let now = Instant::now();
+  let crawler = IpAddr::V4(u32::MAX.into());
+  assert!(limit.check_at(crawler, now).is_ok());
+  assert!(limit.check_at(crawler, now).is_ok());
+  // 大量只请求一次的新 ip 不能把正在被限速的客户端挤掉
+  for i in 0..MAX_BUCKETS as u32 {
+    assert!(limit.check_at(IpAddr::V4(i.into()), now).is_ok());
+  }
+  let len = limit.buckets.lock().unwrap().map.len();
+  assert!(len <= MAX_BUCKETS / 2 + 1);
+  assert!(limit.check_at(crawler, now).is_err());
+}
+
+#[test]
+fn test_concurrency_limit() {
+  let limit = Arc::new(ConcurrencyLimit::new(2));
+  let first = limit.try_acquire();
+  let second = limit.try_acquire();
+  assert!(first.is_some() && second.is_some());
+  assert!(limit.try_acquire().is_none());
+  drop(first);
+  assert!(limit.try_acquire().is_some());
+}
@@ -0,0 +1,333 @@
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_h1::server::ConnectionStatus;
+use async_std::io;
+use async_std::net::{SocketAddr, TcpListener, TcpStream};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::stream::{Stream, StreamExt};
+use async_std::task;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use log::{debug, error};
+use tide::Server;
+
+use crate::http2::{accept_h2, PREFACE};
+use crate::limit::ConcurrencyLimit;
+use crate::metrics::METRICS;
+use crate::shutdown::{is_shutting_down, until_shutdown, InFlight};
+use crate::tls::{TlsStore, TlsStreamWrapper};
+
+/// 空闲 keep-alive 连接的超时时间，和 async-h1 默认的 headers 超时一致
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// --listen 的参数，"0.0.0.0:8080"、"[::]:8080" 或 "unix:/run/lightstatic.sock"
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+  Tcp(SocketAddr),
+  Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Some(path) = s.strip_prefix("unix:") {
+      if path.is_empty() {
+        return Err("missing unix socket path".into());
+      }
+      return Ok(ListenAddr::Unix(PathBuf::from(path)));
+    }
+    s.parse().map(ListenAddr::Tcp).map_err(|_| {
+      format!(
+        "invalid address \"{}\", expect \"ip:port\", \"[ipv6]:port\" or \"unix:PATH\"",
+        s
+      )
+    })
+  }
+}
+
+impl fmt::Display for ListenAddr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+      ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+    }
+  }
+}
+
+pub enum Listener {
+  Tcp(TcpListener),
+  /// unix socket 一般用于前置代理，只提供明文 HTTP/1.1
+  Unix(UnixListener),
+}
+
+impl Listener {
+  /// 实际绑定的地址，端口为 0 或自动选择端口时和参数不同
+  pub fn local_addr(&self) -> io::Result<ListenAddr> {
+    match self {
+      Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+      Listener::Unix(listener) => {
+        let addr = listener.local_addr()?;
+        let path = addr
+          .as_pathname()
+          .ok_or_else(|| io::Error::other("unnamed unix socket"))?;
+        Ok(ListenAddr::Unix(path.into()))
+      }
+    }
+  }
+}
+
+impl From<TcpListener> for Listener {
+  fn from(listener: TcpListener) -> Self {
+    Listener::Tcp(listener)
+  }
+}
+
+impl From<UnixListener> for Listener {
+  fn from(listener: UnixListener) -> Self {
+    Listener::Unix(listener)
+  }
+}
+
+#[derive(Clone, Default)]
+pub struct ServeOptions {
+  pub tls: Option<Arc<TlsStore>>,
+  /// 允许 HTTP/2：TLS 下通过 ALPN 协商，明文下支持 h2c prior knowledge
+  pub http2: bool,
+  /// 所有 listener 共用，达到上限后新连接会被立即关闭
+  pub max_connections: Option<Arc<ConcurrencyLimit>>,
+}
+
+/// 替代 tide 自带的 listener，接受 TCP 连接后根据是否配置了证书决定是否先进行 TLS 握手。
+pub async fn serve<State: Clone + Send + Sync + 'static>(
+  app: Server<State>,
+  listener: impl Into<Listener>,
+  options: ServeOptions,
+) -> io::Result<()> {
+  match listener.into() {
+    Listener::Tcp(listener) => {
+      accept_loop(listener.incoming(), &options.max_connections, |stream| {
+        handle_connection(app.clone(), stream, options.clone())
+      })
+      .await
+    }
+    Listener::Unix(listener) => {
+      accept_loop(listener.incoming(), &options.max_connections, |stream| {
+        handle_unix_connection(app.clone(), stream)
+      })
+      .await
+    }
+  }
+  Ok(())
+}
+
+async fn accept_loop<S, F, Fut>(
+  mut incoming: impl Stream<Item = io::Result<S>> + Unpin,
+  max_connections: &Option<Arc<ConcurrencyLimit>>,
+  handle: F,
+) where
+  F: Fn(S) -> Fut,
+  Fut: Future<Output = ()> + Send + 'static,
+{
+  // 收到退出请求后停止接受新连接，listener 随之关闭
+  while let Some(stream) = until_shutdown(incoming.next()).await.flatten() {
+    match stream {
+      Err(ref err) if is_transient_error(err) => continue,
+      Err(err) => {
+        let delay = Duration::from_millis(500);
+        error!(
+          "failed accept connection due to {}, pausing for {:?}",
+          err, delay
+        );
+        task::sleep(delay).await;
+      }
+      Ok(stream) => {
+        let permit = match max_connections {
+          Some(limit) => match limit.try_acquire() {
+            Some(permit) => Some(permit),
+            None => {
+              // drop 即关闭连接
+              METRICS.connection_rejected();
+              debug!("too many connections, closing new connection");
+              continue;
+            }
+          },
+          None => None,
+        };
+        let connection = handle(stream);
+        task::spawn(async move {
+          connection.await;
+          drop(permit);
+        });
+      }
+    }
+  }
+}
+
+fn is_transient_error(err: &io::Error) -> bool {
+  use io::ErrorKind::*;
+  matches!(
+    err.kind(),
+    ConnectionRefused | ConnectionAborted | ConnectionReset
+  )
+}
+
+async fn handle_connection<State: Clone + Send + Sync + 'static>(
+  app: Server<State>,
+  stream: TcpStream,
+  options: ServeOptions,
+) {
+  let local_addr = stream.local_addr().ok();
+  let peer_addr = stream.peer_addr().ok();
+  METRICS.connection_opened();
+  let result = match options.tls {
+    None => {
+      if options.http2 && is_h2c_preface(&stream).await {
+        accept_h2(app, stream, local_addr, peer_addr, false).await
+      } else {
+        accept_h1(app, stream, local_addr, peer_addr).await
+      }
+    }
+    Some(tls) => match tls.acceptor().accept(stream).await {
+      Ok(stream) => {
+        if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+          accept_h2(app, stream, local_addr, peer_addr, true).await
+        } else {
+          accept_h1(app, TlsStreamWrapper::new(stream), local_addr, peer_addr).await
+        }
+      }
+      Err(err) => Err(err.into()),
+    },
+  };
+  if let Err(err) = result {
+    // 客户端断开、TLS 握手失败等都属于连接层面的错误，通常不需要关注
+    debug!("connection error: {}", err);
+  }
+  METRICS.connection_closed();
+}
+
+async fn handle_unix_connection<State: Clone + Send + Sync + 'static>(
+  app: Server<State>,
+  stream: UnixStream,
+) {
+  METRICS.connection_opened();
+  if let Err(err) = accept_h1(app, stream, None, None).await {
+    debug!("connection error: {}", err);
+  }
+  METRICS.connection_closed();
+}
+
+/// 不消费数据，只窥视开头的字节。HTTP/1.x 不存在以 "PRI " 开头的请求，匹配到前缀即可认为是 h2c。
+async fn is_h2c_preface(stream: &TcpStream) -> bool {
+  let mut buf = [0u8; 24];
+  match stream.peek(&mut buf).await {
+    Ok(size) if size >= 4 => PREFACE.starts_with(&buf[..size]),
+    _ => false,
+  }
+}
+
+/// 逐个处理 keep-alive 连接上的请求。先读出下一个请求的第一个字节再交给 async-h1，
+/// 这样退出时可以直接关闭空闲连接，而正在处理的请求会计入 InFlight。
+async fn accept_h1<State, IO>(
+  app: Server<State>,
+  io: IO,
+  local_addr: Option<SocketAddr>,
+  peer_addr: Option<SocketAddr>,
+) -> http_types::Result<()>
+where
+  State: Clone + Send + Sync + 'static,
+  IO: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+  let mut reader = io.clone();
+  let io = Prefixed {
+    io,
+    first: Arc::new(Mutex::new(None)),
+  };
+  let first = io.first.clone();
+  let mut server = async_h1::server::Server::new(io, |mut req| async {
+    req.set_local_addr(local_addr);
+    req.set_peer_addr(peer_addr);
+    app.respond(req).await
+  });
+  loop {
+    let mut buf = [0u8; 1];
+    // 对端关闭、空闲超时或收到退出请求时关闭连接
+    match until_shutdown(io::timeout(KEEP_ALIVE_TIMEOUT, reader.read(&mut buf))).await {
+      Some(Ok(1)) => *first.lock().unwrap() = Some(buf[0]),
+      _ => return Ok(()),
+    }
+    let _in_flight = InFlight::start();
+    if server.accept_one().await? == ConnectionStatus::Close || is_shutting_down() {
+      return Ok(());
+    }
+  }
+}
+
+/// 把等待请求时读出的第一个字节放回流的开头
+#[derive(Clone)]
+struct Prefixed<IO> {
+  io: IO,
+  first: Arc<Mutex<Option<u8>>>,
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Prefixed<IO> {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+  ) -> Poll<io::Result<usize>> {
+    if !buf.is_empty() {
+      if let Some(byte) = self.first.lock().unwrap().take() {
+        buf[0] = byte;
+        return Poll::Ready(Ok(1));
+      }
+    }
+    Pin::new(&mut self.io).poll_read(cx, buf)
+  }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Prefixed<IO> {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.io).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.io).poll_flush(cx)
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.io).poll_close(cx)
+  }
+}
+
+#[test]
+fn test_listen_addr() {
+  assert_eq!(
+    Ok(ListenAddr::Tcp("127.0.0.1:8080".parse().unwrap())),
+    "127.0.0.1:8080".parse()
+  );
+  assert_eq!(
+    Ok(ListenAddr::Tcp("[::1]:8080".parse().unwrap())),
+    "[::1]:8080".parse()
+  );
+  assert_eq!(
+    Ok(ListenAddr::Unix(PathBuf::from("/run/lightstatic.sock"))),
+    "unix:/run/lightstatic.sock".parse()
+  );
+  assert!("8080".parse::<ListenAddr>().is_err());
+  assert!("unix:".parse::<ListenAddr>().is_err());
+  assert_eq!(
+    "unix:/run/lightstatic.sock",
+    ListenAddr::Unix(PathBuf::from("/run/lightstatic.sock")).to_string()
+  );
+}
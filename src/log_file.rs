@@ -0,0 +1,296 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// 所有打开的日志文件，收到 SIGHUP 时统一重新打开（配合外部 logrotate 使用）。
+static LOG_FILES: Mutex<Vec<LogFile>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Debug, Default)]
+pub struct RotateOptions {
+  /// 单个日志文件超过该大小后滚动
+  pub max_size: Option<u64>,
+  /// 最多保留的历史日志文件数
+  pub keep: Option<usize>,
+  /// 历史日志文件最长保留时间
+  pub max_age: Option<Duration>,
+  /// 历史文件（按大小滚动或者按天切换后）使用 gzip 压缩
+  pub compress: bool,
+}
+
+/// 按天（UTC）命名的日志文件：`{dir}/{name}.%Y-%m-%d.log`，超过大小时滚动为 `{name}.%Y-%m-%d.{n}.log[.gz]`。
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<LogFileInner>>);
+
+struct LogFileInner {
+  dir: PathBuf,
+  name: String,
+  options: RotateOptions,
+  date: String,
+  file: File,
+  size: u64,
+  /// 上一次写入以换行结尾。fern 一行日志会分多次 write，只在行首滚动，避免一行被拆到两个文件里
+  line_start: bool,
+}
+
+impl LogFile {
+  pub fn open(dir: &Path, name: &str, options: RotateOptions) -> io::Result<Self> {
+    let date = today();
+    let (file, size) = open_append(&active_path(dir, name, &date))?;
+    let log_file = LogFile(Arc::new(Mutex::new(LogFileInner {
+      dir: dir.to_path_buf(),
+      name: name.to_string(),
+      options,
+      date,
+      file,
+      size,
+      line_start: true,
+    })));
+    log_file.0.lock().unwrap().cleanup();
+    LOG_FILES.lock().unwrap().push(log_file.clone());
+    Ok(log_file)
+  }
+}
+
+impl Write for LogFile {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let mut inner = self.0.lock().unwrap();
+    if inner.line_start {
+      let date = today();
+      if date != inner.date {
+        let previous = active_path(&inner.dir, &inner.name, &inner.date);
+        inner.date = date;
+        inner.reopen()?;
+        inner.archive(previous);
+      } else if inner
+        .options
+        .max_size
+        .is_some_and(|max_size| inner.size > 0 && inner.size + buf.len() as u64 > max_size)
+      {
+        inner.rotate()?;
+      }
+    }
+    let size = inner.file.write(buf)?;
+    inner.size += size as u64;
+    if size > 0 {
+      inner.line_start = buf[size - 1] == b'\n';
+    }
+    Ok(size)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.0.lock().unwrap().file.flush()
+  }
+}
+
+impl LogFileInner {
+  fn reopen(&mut self) -> io::Result<()> {
+    let (file, size) = open_append(&active_path(&self.dir, &self.name, &self.date))?;
+    self.file = file;
+    self.size = size;
+    Ok(())
+  }
+
+  fn rotate(&mut self) -> io::Result<()> {
+    let active = active_path(&self.dir, &self.name, &self.date);
+    let mut n = 1;
+    let rotated = loop {
+      let rotated = self
+        .dir
+        .join(format!("{}.{}.{}.log", self.name, self.date, n));
+      if !rotated.exists() && !gz_path(&rotated).exists() {
+        break rotated;
+      }
+      n += 1;
+    };
+    fs::rename(&active, &rotated)?;
+    self.reopen()?;
+    self.archive(rotated);
+    Ok(())
+  }
+
+  /// 不再写入的历史文件（按大小滚动或者日期变化），按需压缩后清理
+  fn archive(&self, history: PathBuf) {
+    if self.options.compress {
+      // 压缩可能比较耗时，放到独立线程里，压缩完成后再清理
+      let cleaner = self.cleaner();
+      std::thread::spawn(move || {
+        if let Err(err) = compress(&history) {
+          eprintln!("failed compress {} due to {}", history.display(), err);
+        }
+        cleaner.cleanup();
+      });
+    } else {
+      self.cleanup();
+    }
+  }
+
+  fn cleaner(&self) -> Cleaner {
+    Cleaner {
+      dir: self.dir.clone(),
+      name: self.name.clone(),
+      active: active_path(&self.dir, &self.name, &self.date),
+      options: self.options.clone(),
+    }
+  }
+
+  fn cleanup(&self) {
+    self.cleaner().cleanup();
+  }
+}
+
+/// 按 --log-keep 和 --log-max-age 删除历史日志文件
+struct Cleaner {
+  dir: PathBuf,
+  name: String,
+  active: PathBuf,
+  options: RotateOptions,
+}
+
+impl Cleaner {
+  fn cleanup(&self) {
+    if self.options.keep.is_none() && self.options.max_age.is_none() {
+      return;
+    }
+    let prefix = format!("{}.", self.name);
+    let Ok(entries) = fs::read_dir(&self.dir) else {
+      return;
+    };
+    let mut history: Vec<(SystemTime, PathBuf)> = entries
+      .filter_map(|entry| entry.ok())
+      .filter(|entry| {
+        entry.file_name().to_str().is_some_and(|file_name| {
+          file_name.starts_with(&prefix)
+            && (file_name.ends_with(".log") || file_name.ends_with(".log.gz"))
+        })
+      })
+      .map(|entry| entry.path())
+      .filter(|path| path != &self.active)
+      .filter_map(|path| {
+        let mtime = path.metadata().and_then(|stat| stat.modified()).ok()?;
+        Some((mtime, path))
+      })
+      .collect();
+    // 新的在前
+    history.sort_by_key(|(mtime, _)| std::cmp::Reverse(*mtime));
+    let now = SystemTime::now();
+    for (idx, (mtime, path)) in history.iter().enumerate() {
+      let too_many = self.options.keep.is_some_and(|keep| idx >= keep);
+      let too_old = self
+        .options
+        .max_age
+        .is_some_and(|max_age| now.duration_since(*mtime).is_ok_and(|age| age > max_age));
+      if too_many || too_old {
+        if let Err(err) = fs::remove_file(path) {
+          eprintln!("failed remove log file {} due to {}", path.display(), err);
+        }
+      }
+    }
+  }
+}
+
+/// 重新打开所有日志文件，外部工具（如 logrotate）移走文件后调用。
+pub fn reopen_log_files() -> io::Result<()> {
+  for log_file in LOG_FILES.lock().unwrap().iter() {
+    log_file.0.lock().unwrap().reopen()?;
+  }
+  Ok(())
+}
+
+fn today() -> String {
+  chrono::Utc::now().format("%Y-%m-%d").to_string()
+}
+
+fn active_path(dir: &Path, name: &str, date: &str) -> PathBuf {
+  dir.join(format!("{}.{}.log", name, date))
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+  let mut gz = path.as_os_str().to_owned();
+  gz.push(".gz");
+  PathBuf::from(gz)
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+  let file = File::options().append(true).create(true).open(path)?;
+  let size = file.metadata()?.len();
+  Ok((file, size))
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+  let mut input = File::open(path)?;
+  let mut gz = GzEncoder::new(File::create(gz_path(path))?, Compression::default());
+  io::copy(&mut input, &mut gz)?;
+  gz.finish()?;
+  fs::remove_file(path)
+}
+
+#[test]
+fn test_rotate() {
+  let dir = std::env::temp_dir().join(format!("lightstatic-log-test-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let mut log_file = LogFile::open(
+    &dir,
+    "access",
+    RotateOptions {
+      max_size: Some(10),
+      keep: Some(2),
+      ..Default::default()
+    },
+  )
+  .unwrap();
+  for _ in 0..5 {
+    log_file.write_all(b"012345678\n").unwrap();
+  }
+  // 同一行分多次写入时不会在中间滚动
+  log_file.write_all(b"0123").unwrap();
+  log_file.write_all(b"45678\n").unwrap();
+  let mut files: Vec<_> = fs::read_dir(&dir)
+    .unwrap()
+    .map(|e| e.unwrap().file_name().into_string().unwrap())
+    .collect();
+  files.sort();
+  let date = today();
+  // 当前文件 + 最多保留 2 个历史文件
+  assert_eq!(3, files.len());
+  assert!(files.contains(&format!("access.{}.log", date)));
+  assert_eq!(
+    "012345678\n",
+    fs::read_to_string(active_path(&dir, "access", &date)).unwrap()
+  );
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_compress_on_date_change() {
+  let dir = std::env::temp_dir().join(format!("lightstatic-log-date-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let mut log_file = LogFile::open(
+    &dir,
+    "info",
+    RotateOptions {
+      compress: true,
+      ..Default::default()
+    },
+  )
+  .unwrap();
+  log_file.0.lock().unwrap().date = "2000-01-01".to_string();
+  log_file.0.lock().unwrap().reopen().unwrap();
+  log_file.write_all(b"old day\n").unwrap();
+  let previous = active_path(&dir, "info", "2000-01-01");
+  // 日期和 today() 不同，下一次写入时切换到新文件并压缩之前的文件
+  log_file.write_all(b"new day\n").unwrap();
+  for _ in 0..100 {
+    if gz_path(&previous).exists() && !previous.exists() {
+      break;
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  }
+  assert!(gz_path(&previous).exists() && !previous.exists());
+  assert!(active_path(&dir, "info", &today()).exists());
+  fs::remove_dir_all(&dir).unwrap();
+}
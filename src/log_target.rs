@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+
+use log::kv::{self, Key, Value, VisitSource, VisitValue};
+use log::{Level, Record};
+use syslog::{Facility, Formatter5424};
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// RFC5424 structured data 的 SD-ID，32473 是 RFC5612 中用于示例和文档的企业编号
+const SYSLOG_SD_ID: &str = "lightstatic@32473";
+
+/// info、error、access 三类日志，可以分别输出到不同的目标
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogStream {
+  Info,
+  Error,
+  Access,
+}
+
+impl LogStream {
+  pub fn name(&self) -> &'static str {
+    match self {
+      LogStream::Info => "info",
+      LogStream::Error => "error",
+      LogStream::Access => "access",
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogTarget {
+  /// info 和 access 输出到 stdout，error 输出到 stderr
+  Stdout,
+  /// 写入 --log-dir 下的日志文件
+  File,
+  Syslog,
+  Journald,
+}
+
+/// --log-target 的参数，形如 "syslog"（所有日志）或 "access=file"（单类日志）
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogTargetSpec {
+  pub stream: Option<LogStream>,
+  pub target: LogTarget,
+}
+
+impl FromStr for LogTargetSpec {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (stream, target) = match s.split_once('=') {
+      Some((stream, target)) => (Some(stream), target),
+      None => (None, s),
+    };
+    let stream = match stream {
+      None => None,
+      Some("info") => Some(LogStream::Info),
+      Some("error") => Some(LogStream::Error),
+      Some("access") => Some(LogStream::Access),
+      Some(stream) => {
+        return Err(format!(
+          "unknown log stream \"{}\", expect \"info\", \"error\" or \"access\"",
+          stream
+        ))
+      }
+    };
+    let target = match target {
+      "stdout" => LogTarget::Stdout,
+      "file" => LogTarget::File,
+      "syslog" => LogTarget::Syslog,
+      "journald" => LogTarget::Journald,
+      _ => {
+        return Err(format!(
+          "unknown log target \"{}\", expect \"stdout\", \"file\", \"syslog\" or \"journald\"",
+          target
+        ))
+      }
+    };
+    Ok(LogTargetSpec { stream, target })
+  }
+}
+
+/// 按参数顺序计算某类日志的输出目标，后面的参数覆盖前面的。
+/// 未指定时，有 --log-dir 则写文件，否则输出到 stdout。
+pub fn resolve_target(specs: &[LogTargetSpec], stream: LogStream, has_log_dir: bool) -> LogTarget {
+  specs
+    .iter()
+    .rev()
+    .find(|spec| spec.stream.is_none_or(|s| s == stream))
+    .map(|spec| spec.target)
+    .unwrap_or(if has_log_dir {
+      LogTarget::File
+    } else {
+      LogTarget::Stdout
+    })
+}
+
+pub fn syslog_output(stream: LogStream) -> io::Result<fern::Output> {
+  let formatter = Formatter5424 {
+    facility: Facility::LOG_DAEMON,
+    hostname: None,
+    process: "lightstatic".into(),
+    pid: std::process::id(),
+  };
+  let logger = syslog::unix(formatter).map_err(|err| io::Error::other(err.to_string()))?;
+  Ok(fern::Output::syslog6_5424(logger, move |record| {
+    let mut fields = FieldCollector::default();
+    let _ = record.key_values().visit(&mut fields);
+    let mut data = HashMap::new();
+    if !fields.0.is_empty() {
+      let mut params: HashMap<String, String> = fields.0.into_iter().collect();
+      params.insert("stream".into(), stream.name().into());
+      data.insert(SYSLOG_SD_ID.to_string(), params);
+    }
+    (0, data, record.args().to_string())
+  }))
+}
+
+pub fn journald_output(stream: LogStream) -> io::Result<fern::Output> {
+  let socket = UnixDatagram::unbound()?;
+  socket.connect(JOURNALD_SOCKET)?;
+  Ok(fern::Output::call(move |record| {
+    if let Err(err) = socket.send(&encode_journald(record, stream)) {
+      eprintln!("failed send log to journald due to {}", err);
+    }
+  }))
+}
+
+/// journald 原生协议：每行一个 KEY=value 字段，值中含换行时使用长度前缀的二进制格式
+fn encode_journald(record: &Record, stream: LogStream) -> Vec<u8> {
+  let priority = match record.level() {
+    Level::Error => "3",
+    Level::Warn => "4",
+    Level::Info => "6",
+    Level::Debug | Level::Trace => "7",
+  };
+  let mut buf = Vec::new();
+  append_journald_field(&mut buf, "PRIORITY", priority);
+  append_journald_field(&mut buf, "SYSLOG_IDENTIFIER", "lightstatic");
+  append_journald_field(&mut buf, "SYSLOG_PID", &std::process::id().to_string());
+  append_journald_field(&mut buf, "CODE_MODULE", record.target());
+  append_journald_field(&mut buf, "LIGHTSTATIC_STREAM", stream.name());
+  let mut fields = FieldCollector::default();
+  let _ = record.key_values().visit(&mut fields);
+  for (key, value) in fields.0.iter() {
+    append_journald_field(&mut buf, &key.to_uppercase(), value);
+  }
+  append_journald_field(&mut buf, "MESSAGE", &record.args().to_string());
+  buf
+}
+
+fn append_journald_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+  buf.extend_from_slice(key.as_bytes());
+  if value.contains('\n') {
+    buf.push(b'\n');
+    buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+  } else {
+    buf.push(b'=');
+  }
+  buf.extend_from_slice(value.as_bytes());
+  buf.push(b'\n');
+}
+
+/// 收集日志记录中的结构化字段（目前只有访问日志带有这些字段）
+#[derive(Default)]
+struct FieldCollector(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for FieldCollector {
+  fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+    // Option::None 记录为 null 值，直接省略该字段。不能比较字符串，"None" 也可能是真实的值
+    let mut is_null = IsNull(false);
+    value.visit(&mut is_null)?;
+    if !is_null.0 {
+      self.0.push((key.to_string(), value.to_string()));
+    }
+    Ok(())
+  }
+}
+
+struct IsNull(bool);
+
+impl<'v> VisitValue<'v> for IsNull {
+  fn visit_any(&mut self, _: Value) -> Result<(), kv::Error> {
+    Ok(())
+  }
+
+  fn visit_null(&mut self) -> Result<(), kv::Error> {
+    self.0 = true;
+    Ok(())
+  }
+}
+
+#[test]
+fn test_log_target() {
+  let specs: Vec<LogTargetSpec> = ["file", "error=syslog", "access=journald"]
+    .iter()
+    .map(|s| s.parse().unwrap())
+    .collect();
+  assert_eq!(
+    LogTarget::File,
+    resolve_target(&specs, LogStream::Info, true)
+  );
+  assert_eq!(
+    LogTarget::Syslog,
+    resolve_target(&specs, LogStream::Error, true)
+  );
+  assert_eq!(
+    LogTarget::Journald,
+    resolve_target(&specs, LogStream::Access, true)
+  );
+  assert_eq!(
+    LogTarget::Stdout,
+    resolve_target(&[], LogStream::Info, false)
+  );
+  assert!("debug=file".parse::<LogTargetSpec>().is_err());
+  assert!("kafka".parse::<LogTargetSpec>().is_err());
+}
+
+#[test]
+fn test_encode_journald() {
+  let record = Record::builder()
+    .args(format_args!("multi\nline"))
+    .level(Level::Warn)
+    .target("lightstatic::server")
+    .build();
+  let buf = encode_journald(&record, LogStream::Error);
+  let text = String::from_utf8_lossy(&buf);
+  assert!(text.starts_with("PRIORITY=4\nSYSLOG_IDENTIFIER=lightstatic\n"));
+  assert!(text.contains("LIGHTSTATIC_STREAM=error\n"));
+  let mut expect = b"MESSAGE\n".to_vec();
+  expect.extend_from_slice(&10u64.to_le_bytes());
+  expect.extend_from_slice(b"multi\nline\n");
+  assert!(buf.ends_with(&expect));
+}
+
+#[test]
+fn test_field_collector() {
+  use log::kv::ToValue;
+  let query: Option<&str> = None;
+  let user_agent = Some("None");
+  let kvs: [(&str, Value); 3] = [
+    ("query", query.to_value()),
+    ("user_agent", user_agent.to_value()),
+    ("status", 200u16.to_value()),
+  ];
+  let mut fields = FieldCollector::default();
+  kv::Source::visit(&kvs[..], &mut fields).unwrap();
+  assert_eq!(
+    vec![
+      ("user_agent".to_string(), "None".to_string()),
+      ("status".to_string(), "200".to_string())
+    ],
+    fields.0
+  );
+}
@@ -1,118 +1,548 @@
 use crate::{
   args,
+  listener::ListenAddr,
+  log_file::{LogFile, RotateOptions},
+  log_target::{journald_output, resolve_target, syslog_output, LogStream, LogTarget},
   util::{self, resolve_path},
 };
 
 use async_std::path::Path;
 use colored::Colorize;
-use fern::{DateBased, Dispatch};
-use log::LevelFilter;
-use std::fmt::Display;
+use fern::{Dispatch, FormatCallback};
+use log::{info, Level, LevelFilter, Record};
+use std::fmt::{Arguments, Display};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// 访问日志使用独立的 target，不受 --log-level 影响，只由 --no-access 控制
+const ACCESS_TARGET: &str = "lightstatic::access";
+
+/// RUST_LOG 风格的日志过滤规则，如 "debug" 或 "info,lightstatic::store=trace,rustls=off"
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFilter {
+  default: LevelFilter,
+  modules: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for LogFilter {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut filter = LogFilter {
+      default: LevelFilter::Info,
+      modules: Vec::new(),
+    };
+    for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+      let parse_level = |level: &str| {
+        level
+          .parse::<LevelFilter>()
+          .map_err(|_| format!("invalid log level \"{}\"", level))
+      };
+      match directive.split_once('=') {
+        Some((module, level)) => filter
+          .modules
+          .push((module.trim().to_string(), parse_level(level.trim())?)),
+        None => match directive.parse::<LevelFilter>() {
+          Ok(level) => filter.default = level,
+          // 只写模块名表示该模块输出所有级别
+          Err(_) => filter
+            .modules
+            .push((directive.to_string(), LevelFilter::Trace)),
+        },
+      }
+    }
+    Ok(filter)
+  }
+}
+
+impl LogFilter {
+  /// 未指定 --log-level 时读取 RUST_LOG 环境变量，默认 info
+  pub fn from_env() -> Self {
+    std::env::var("RUST_LOG")
+      .ok()
+      .and_then(|spec| match spec.parse() {
+        Ok(filter) => Some(filter),
+        Err(err) => {
+          eprintln!("ignore RUST_LOG: {}", err);
+          None
+        }
+      })
+      .unwrap_or_else(|| "info".parse().unwrap())
+  }
+
+  fn apply(&self, mut dispatch: Dispatch) -> Dispatch {
+    dispatch = dispatch.level(self.default);
+    for (module, level) in self.modules.iter() {
+      dispatch = dispatch.level_for(module.clone(), *level);
+    }
+    dispatch
+  }
+}
 
 pub fn initialize_log(args: &args::Args, cwd: &Path) {
-  let mut info_dest = Dispatch::new()
-    .level(LevelFilter::Off)
-    .level_for("lightstatic::info", LevelFilter::Error);
-  // .format(|out, msg, rec| {
-  //   out.finish(format_args!("{} {}", rec.target(), msg))
-  // });
-  let mut error_dest = Dispatch::new()
-    .level(LevelFilter::Off)
-    .level_for("lightstatic::error", LevelFilter::Error);
-  let mut access_dest = Dispatch::new()
-    .level(LevelFilter::Off)
-    .level_for("lightstatic::access", LevelFilter::Error);
-  let mut logger = Dispatch::new().level(LevelFilter::Error);
-  //.level_for("lightstatic::info", LevelFilter::Error).level_for("lightstatic::error", LevelFilter::Error)
-  if let Some(log_dir) = &args.log_dir {
+  let filter = args.log_level.clone().unwrap_or_else(LogFilter::from_env);
+  let log_dir = args.log_dir.as_ref().map(|log_dir| {
     let prefix = resolve_path(cwd, Path::new(log_dir));
-
     if !std::path::Path::new(&prefix).is_dir() {
       panic!("--log-dir not exists or is not directory");
     }
-    info_dest = info_dest
-      .chain(DateBased::new(format!("{}/info.", prefix.display()), "%Y-%m-%d.log").utc_time());
-    error_dest = error_dest
-      .chain(DateBased::new(format!("{}/error.", prefix.display()), "%Y-%m-%d.log").utc_time());
-    access_dest = access_dest
-      .chain(DateBased::new(format!("{}/access.", prefix.display()), "%Y-%m-%d.log").utc_time());
-
-    logger = logger.format(|out, message, _| {
-      out.finish(format_args!(
-        "{} {}",
-        chrono::Utc::now().format("[%H:%M:%S]"),
-        message
-      ))
-    })
-  } else {
-    access_dest = access_dest.chain(std::io::stdout());
-    info_dest = info_dest.chain(std::io::stdout());
-    error_dest = error_dest.chain(std::io::stderr());
-  }
-  logger
-    .chain(access_dest)
+    prefix
+  });
+  let options = RotateOptions {
+    max_size: args.log_max_size,
+    keep: args.log_keep,
+    max_age: args
+      .log_max_age
+      .map(|days| Duration::from_secs(days * 86400)),
+    compress: args.log_compress,
+  };
+  let attach = |dest: Dispatch, stream: LogStream| -> Dispatch {
+    let output: io::Result<Dispatch> =
+      match resolve_target(&args.log_target, stream, log_dir.is_some()) {
+        LogTarget::Stdout if stream == LogStream::Error => Ok(dest.chain(std::io::stderr())),
+        LogTarget::Stdout => Ok(dest.chain(std::io::stdout())),
+        LogTarget::File => {
+          // get_args 中已经校验过 file 目标必须指定 --log-dir
+          let prefix = log_dir.as_ref().unwrap();
+          LogFile::open(prefix.as_ref(), stream.name(), options.clone()).map(|log_file| {
+            // combined/json 等格式自带时间，不再添加前缀
+            if stream == LogStream::Access && args.access_log_format != AccessLogFormat::Default {
+              dest.chain(Box::new(log_file) as Box<dyn Write + Send>)
+            } else {
+              dest
+                .format(format_with_time)
+                .chain(Box::new(log_file) as Box<dyn Write + Send>)
+            }
+          })
+        }
+        LogTarget::Syslog => syslog_output(stream).map(|output| dest.chain(output)),
+        LogTarget::Journald => journald_output(stream).map(|output| dest.chain(output)),
+      };
+    output.unwrap_or_else(|err| panic!("failed open {} log target due to {}", stream.name(), err))
+  };
+  let info_dest = attach(
+    Dispatch::new().filter(|meta| meta.level() > Level::Warn),
+    LogStream::Info,
+  );
+  let error_dest = attach(
+    Dispatch::new().filter(|meta| meta.level() <= Level::Warn),
+    LogStream::Error,
+  );
+  let access_dest = attach(
+    Dispatch::new().filter(|meta| meta.target() == ACCESS_TARGET),
+    LogStream::Access,
+  );
+  let general = filter
+    .apply(Dispatch::new())
+    .filter(|meta| meta.target() != ACCESS_TARGET)
+    .format(format_with_level)
     .chain(info_dest)
-    .chain(error_dest)
+    .chain(error_dest);
+  Dispatch::new()
+    .chain(access_dest)
+    .chain(general)
     .apply()
     .unwrap();
 }
 
-#[macro_export]
-macro_rules! info {
-  ($($arg:tt)+) => (log::log!(target: "lightstatic::info", log::Level::Error, $($arg)+))
+/// info 级别保持简洁的输出，其它级别带上级别和来源模块
+fn format_with_level(out: FormatCallback, message: &Arguments, record: &Record) {
+  if record.level() == Level::Info {
+    out.finish(format_args!("{}", message))
+  } else {
+    out.finish(format_args!(
+      "{} {}: {}",
+      record.level(),
+      record.target(),
+      message
+    ))
+  }
 }
 
-#[macro_export]
-macro_rules! error {
-  ($($arg:tt)+) => (log::log!(target: "lightstatic::error", log::Level::Error, $($arg)+))
+fn format_with_time(out: FormatCallback, message: &Arguments, _: &Record) {
+  out.finish(format_args!(
+    "{} {}",
+    chrono::Utc::now().format("[%H:%M:%S]"),
+    message
+  ))
 }
 
-fn log_listening<T: Display>(ip: T, port: u16, base: &Option<String>) {
+fn log_listening<T: Display>(scheme: &str, ip: T, port: u16, base: &Option<String>) {
   if let Some(x) = base.as_ref() {
     if !util::is_empty_root_url(x) {
-      return info!("  http://{}:{}  with base href {}", ip, port, x.yellow());
+      return info!(
+        "  {}://{}:{}  with base href {}",
+        scheme,
+        ip,
+        port,
+        x.yellow()
+      );
     }
   };
-  info!("  http://{}:{}", ip, port);
+  info!("  {}://{}:{}", scheme, ip, port);
 }
 
-pub fn log_startup_info(args: &args::Args, cached_store_file_size: Option<usize>) {
+pub fn log_startup_info(
+  args: &args::Args,
+  addrs: &[ListenAddr],
+  cached_store_file_size: Option<usize>,
+) {
   let cached_store_file_size =
     cached_store_file_size.map_or("".into(), |s| format!(", with {} files cached.", s));
+  let serving = args
+    .serve_path
+    .iter()
+    .map(|path| path.as_str())
+    .chain(args.mount.iter().map(|spec| spec.dir.as_str()))
+    .chain(args.vhost.iter().map(|spec| spec.mount.dir.as_str()))
+    .collect::<Vec<_>>()
+    .join(", ");
   info!(
     "{}{}{}",
     "Starting up lightstatic, serving: ".yellow(),
-    args.serve_path.as_ref().unwrap().cyan(),
+    serving.cyan(),
     cached_store_file_size.yellow()
   );
+  for spec in args.mount.iter() {
+    info!("  {} => {}", spec.prefix.cyan(), spec.dir);
+  }
+  for spec in args.vhost.iter() {
+    info!("  {} => {}", spec.host.to_string().cyan(), spec.mount.dir);
+  }
   info!("{}", "Available on:".yellow());
-  let bind_all = args.host.eq("0.0.0.0");
-  if bind_all {
-    let ip = local_ip_address::local_ip().unwrap();
-    log_listening(ip, args.port, &args.base_href);
-    log_listening("127.0.0.1", args.port, &args.base_href);
-  } else {
-    log_listening(&args.host, args.port, &args.base_href);
+  let scheme = if args.tls_enabled() { "https" } else { "http" };
+  for addr in addrs.iter() {
+    match addr {
+      ListenAddr::Tcp(addr) if addr.ip().is_unspecified() => {
+        let ip = local_ip_address::local_ip().unwrap();
+        log_listening(scheme, ip, addr.port(), &args.base_href);
+        log_listening(scheme, loopback(addr), addr.port(), &args.base_href);
+      }
+      ListenAddr::Tcp(addr) => log_listening(scheme, url_host(addr), addr.port(), &args.base_href),
+      // unix socket 上只提供明文 http
+      ListenAddr::Unix(path) => info!("  http+unix:{}", path.display()),
+    }
   }
   if args.html5 {
     info!("Html5 route mode rewrite to {}", args.index.cyan());
   }
   info!("Press {} to stop it.", "ctrl+c".yellow());
-  if args.open {
-    let host = if bind_all {
-      "127.0.0.1"
+  let tcp_addr = addrs.iter().find_map(|addr| match addr {
+    ListenAddr::Tcp(addr) => Some(addr),
+    ListenAddr::Unix(_) => None,
+  });
+  if let (true, Some(addr)) = (args.open, tcp_addr) {
+    let host = if addr.ip().is_unspecified() {
+      loopback(addr)
     } else {
-      args.host.as_str()
+      url_host(addr)
     };
-    let url = format!("http://{}:{}", host, args.port);
+    let url = format!("{}://{}:{}", scheme, host, addr.port());
     open::that(url).unwrap();
   }
 }
 
-pub fn log_access(status: u16, url: &str) {
-  log::log!(target: "lightstatic::access", log::Level::Error, "{} {} {}", "GET".yellow(), if status < 400 {
-    status.to_string().green()
-  } else {
-    status.to_string().red()
-  }, url.cyan());
+fn loopback(addr: &SocketAddr) -> String {
+  if addr.is_ipv6() { "[::1]" } else { "127.0.0.1" }.to_string()
+}
+
+/// url 中的 ipv6 地址需要加上方括号
+fn url_host(addr: &SocketAddr) -> String {
+  match addr.ip() {
+    IpAddr::V6(ip) => format!("[{}]", ip),
+    ip => ip.to_string(),
+  }
+}
+
+/// 访问日志格式，对应 --access-log-format
+#[derive(Clone, Debug, PartialEq)]
+pub enum AccessLogFormat {
+  /// 带颜色的简短格式：方法、状态码、路径和耗时
+  Default,
+  /// Apache combined log format
+  Combined,
+  /// 每行一个 JSON 对象
+  Json,
+  /// 自定义模板，支持 {remote_addr} {time} {method} {path} {query} {version} {status} {bytes} {duration_ms} {referer} {user_agent}
+  Template(AccessLogTemplate),
+}
+
+/// 解析后的访问日志模板，每个字段的值只写入一次，值里面的 "{status}" 之类不会再被替换
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessLogTemplate(Vec<TemplatePart>);
+
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+  Literal(String),
+  Field(&'static str),
+}
+
+const TEMPLATE_FIELDS: [&str; 11] = [
+  "remote_addr",
+  "time",
+  "method",
+  "path",
+  "query",
+  "version",
+  "status",
+  "bytes",
+  "duration_ms",
+  "referer",
+  "user_agent",
+];
+
+impl AccessLogTemplate {
+  /// 不认识的 {...} 原样保留
+  fn parse(template: &str) -> Self {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+      literal.push_str(&rest[..start]);
+      rest = &rest[start..];
+      let field = rest.find('}').and_then(|end| {
+        TEMPLATE_FIELDS
+          .iter()
+          .find(|field| **field == &rest[1..end])
+          .map(|field| (*field, end))
+      });
+      match field {
+        Some((field, end)) => {
+          if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+          }
+          parts.push(TemplatePart::Field(field));
+          rest = &rest[end + 1..];
+        }
+        None => {
+          literal.push('{');
+          rest = &rest[1..];
+        }
+      }
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+      parts.push(TemplatePart::Literal(literal));
+    }
+    AccessLogTemplate(parts)
+  }
+}
+
+impl FromStr for AccessLogFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "default" => Ok(AccessLogFormat::Default),
+      "combined" => Ok(AccessLogFormat::Combined),
+      "json" => Ok(AccessLogFormat::Json),
+      t if t.contains('{') => Ok(AccessLogFormat::Template(AccessLogTemplate::parse(t))),
+      _ => Err(format!(
+        "unknown access log format \"{}\", expect \"default\", \"combined\", \"json\" or a template containing {{...}} fields",
+        s
+      )),
+    }
+  }
+}
+
+pub struct AccessRecord<'a> {
+  pub method: &'a str,
+  pub path: &'a str,
+  pub query: Option<&'a str>,
+  pub version: &'a str,
+  pub status: u16,
+  /// 响应体长度，流式（如 gzip）响应时未知
+  pub bytes: Option<usize>,
+  pub duration: Duration,
+  pub remote_addr: Option<&'a str>,
+  pub referer: Option<&'a str>,
+  pub user_agent: Option<&'a str>,
+}
+
+impl AccessRecord<'_> {
+  fn duration_ms(&self) -> f64 {
+    self.duration.as_secs_f64() * 1000.0
+  }
+
+  fn format_combined(&self) -> String {
+    let mut request_line = format!("{} {}", self.method, self.path);
+    if let Some(query) = self.query {
+      request_line.push('?');
+      request_line.push_str(query);
+    }
+    format!(
+      "{} - - [{}] \"{} {}\" {} {} \"{}\" \"{}\"",
+      self.remote_addr.unwrap_or("-"),
+      chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+      escape(&request_line),
+      self.version,
+      self.status,
+      self.bytes.map_or("-".into(), |b| b.to_string()),
+      escape(self.referer.unwrap_or("-")),
+      escape(self.user_agent.unwrap_or("-"))
+    )
+  }
+
+  fn format_json(&self) -> String {
+    serde_json::json!({
+      "time": chrono::Local::now().to_rfc3339(),
+      "remote_addr": self.remote_addr,
+      "method": self.method,
+      "path": self.path,
+      "query": self.query,
+      "version": self.version,
+      "status": self.status,
+      "bytes": self.bytes,
+      "duration_ms": self.duration_ms(),
+      "referer": self.referer,
+      "user_agent": self.user_agent,
+    })
+    .to_string()
+  }
+
+  fn format_template(&self, template: &AccessLogTemplate) -> String {
+    let mut line = String::new();
+    for part in template.0.iter() {
+      let value = match part {
+        TemplatePart::Literal(literal) => {
+          line.push_str(literal);
+          continue;
+        }
+        TemplatePart::Field("remote_addr") => self.remote_addr.unwrap_or("-").into(),
+        TemplatePart::Field("time") => chrono::Local::now().to_rfc3339(),
+        TemplatePart::Field("method") => escape(self.method),
+        TemplatePart::Field("path") => escape(self.path),
+        TemplatePart::Field("query") => escape(self.query.unwrap_or("")),
+        TemplatePart::Field("version") => self.version.into(),
+        TemplatePart::Field("status") => self.status.to_string(),
+        TemplatePart::Field("bytes") => self.bytes.map_or("-".into(), |b| b.to_string()),
+        TemplatePart::Field("duration_ms") => format!("{:.3}", self.duration_ms()),
+        TemplatePart::Field("referer") => escape(self.referer.unwrap_or("-")),
+        TemplatePart::Field("user_agent") => escape(self.user_agent.unwrap_or("-")),
+        TemplatePart::Field(_) => unreachable!(),
+      };
+      line.push_str(&value);
+    }
+    line
+  }
+}
+
+/// 和 apache/nginx 一样转义客户端可控的值中的 '"'、'\\' 和控制字符，避免伪造日志字段或换行
+fn escape(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02X}", c as u32)),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+pub fn log_access(format: &AccessLogFormat, record: &AccessRecord) {
+  let line = match format {
+    AccessLogFormat::Default => {
+      let status = record.status.to_string();
+      format!(
+        "{} {} {} {}",
+        record.method.yellow(),
+        if record.status < 400 {
+          status.green()
+        } else {
+          status.red()
+        },
+        record.path.cyan(),
+        format!("{:.2}ms", record.duration_ms()).dimmed()
+      )
+    }
+    AccessLogFormat::Combined => record.format_combined(),
+    AccessLogFormat::Json => record.format_json(),
+    AccessLogFormat::Template(template) => record.format_template(template),
+  };
+  // 结构化字段供 syslog/journald 使用
+  log::info!(
+    target: ACCESS_TARGET,
+    remote_addr = record.remote_addr,
+    method = record.method,
+    path = record.path,
+    query = record.query,
+    status = record.status,
+    bytes = record.bytes,
+    duration_ms = record.duration_ms(),
+    referer = record.referer,
+    user_agent = record.user_agent;
+    "{}", line
+  );
+}
+
+#[test]
+fn test_log_filter() {
+  let filter: LogFilter = "warn,lightstatic::store=trace, rustls".parse().unwrap();
+  assert_eq!(LevelFilter::Warn, filter.default);
+  assert_eq!(
+    vec![
+      ("lightstatic::store".to_string(), LevelFilter::Trace),
+      ("rustls".to_string(), LevelFilter::Trace)
+    ],
+    filter.modules
+  );
+  assert_eq!(LevelFilter::Info, "".parse::<LogFilter>().unwrap().default);
+  assert!("lightstatic=loud".parse::<LogFilter>().is_err());
+}
+
+#[test]
+fn test_access_log_format() {
+  let record = AccessRecord {
+    method: "GET",
+    path: "/assets/app.js",
+    query: Some("v=1"),
+    version: "HTTP/1.1",
+    status: 304,
+    bytes: None,
+    duration: Duration::from_micros(1500),
+    remote_addr: Some("10.0.0.1"),
+    referer: None,
+    user_agent: Some("curl/8.0"),
+  };
+  assert_eq!(
+    Ok(AccessLogFormat::Template(AccessLogTemplate(vec![
+      TemplatePart::Literal("{x} ".into()),
+      TemplatePart::Field("method")
+    ]))),
+    "{x} {method}".parse::<AccessLogFormat>()
+  );
+  assert!("apache".parse::<AccessLogFormat>().is_err());
+  assert_eq!(
+    "10.0.0.1 GET /assets/app.js?v=1 304 - 1.500",
+    record.format_template(&AccessLogTemplate::parse(
+      "{remote_addr} {method} {path}?{query} {status} {bytes} {duration_ms}"
+    ))
+  );
+  assert!(record
+    .format_combined()
+    .ends_with("\"GET /assets/app.js?v=1 HTTP/1.1\" 304 - \"-\" \"curl/8.0\""));
+
+  // 客户端可控的值不会再被当作模板字段，也不能伪造引号和换行
+  let forged = AccessRecord {
+    query: Some("{status}"),
+    remote_addr: None,
+    user_agent: Some("{remote_addr}\" \\ \n"),
+    ..record
+  };
+  let template = AccessLogTemplate::parse("{remote_addr} {query} {status} \"{user_agent}\"");
+  assert_eq!(
+    "- {status} 304 \"{remote_addr}\\\" \\\\ \\x0A\"",
+    forged.format_template(&template)
+  );
+  assert!(forged
+    .format_combined()
+    .ends_with("304 - \"-\" \"{remote_addr}\\\" \\\\ \\x0A\""));
+  let json: serde_json::Value = serde_json::from_str(&record.format_json()).unwrap();
+  assert_eq!(json["status"], 304);
+  assert_eq!(json["bytes"], serde_json::Value::Null);
+  assert_eq!(json["user_agent"], "curl/8.0");
 }
@@ -0,0 +1,292 @@
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ahash::AHashMap;
+use tide::{Next, Request, Response};
+
+use crate::config::AppConfig;
+
+/// 请求耗时直方图的桶（秒）
+const DURATION_BUCKETS: [f64; 12] = [
+  0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// 进程内的统计数据，计数器使用原子变量，按状态码和路径分类的请求数需要加锁，仅在指定 --metrics-addr 时对外暴露。
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+  /// (status, path class) => count
+  requests: Mutex<Option<AHashMap<(u16, &'static str), u64>>>,
+  response_bytes: AtomicU64,
+  duration_buckets: [AtomicU64; DURATION_BUCKETS.len()],
+  duration_count: AtomicU64,
+  duration_sum_micros: AtomicU64,
+  cache_hits: AtomicU64,
+  cache_misses: AtomicU64,
+  cache_refreshes: AtomicU64,
+  cache_refresh_micros: AtomicU64,
+  open_connections: AtomicI64,
+  rejected_connections: AtomicU64,
+}
+
+impl Metrics {
+  const fn new() -> Self {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    Metrics {
+      requests: Mutex::new(None),
+      response_bytes: ZERO,
+      duration_buckets: [ZERO; DURATION_BUCKETS.len()],
+      duration_count: ZERO,
+      duration_sum_micros: ZERO,
+      cache_hits: ZERO,
+      cache_misses: ZERO,
+      cache_refreshes: ZERO,
+      cache_refresh_micros: ZERO,
+      open_connections: AtomicI64::new(0),
+      rejected_connections: ZERO,
+    }
+  }
+
+  pub fn record_request(&self, path: &str, status: u16, bytes: Option<usize>, duration: Duration) {
+    *self
+      .requests
+      .lock()
+      .unwrap()
+      .get_or_insert_with(AHashMap::new)
+      .entry((status, path_class(path)))
+      .or_insert(0) += 1;
+    if let Some(bytes) = bytes {
+      self
+        .response_bytes
+        .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    let secs = duration.as_secs_f64();
+    for (idx, le) in DURATION_BUCKETS.iter().enumerate() {
+      if secs <= *le {
+        self.duration_buckets[idx].fetch_add(1, Ordering::Relaxed);
+      }
+    }
+    self.duration_count.fetch_add(1, Ordering::Relaxed);
+    self
+      .duration_sum_micros
+      .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+  }
+
+  pub fn record_cache(&self, hit: bool) {
+    if hit {
+      self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  /// (hits, misses)
+  pub fn cache_counts(&self) -> (u64, u64) {
+    (
+      self.cache_hits.load(Ordering::Relaxed),
+      self.cache_misses.load(Ordering::Relaxed),
+    )
+  }
+
+  pub fn record_cache_refresh(&self, duration: Duration) {
+    self.cache_refreshes.fetch_add(1, Ordering::Relaxed);
+    self
+      .cache_refresh_micros
+      .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+  }
+
+  pub fn connection_opened(&self) {
+    self.open_connections.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn connection_closed(&self) {
+    self.open_connections.fetch_sub(1, Ordering::Relaxed);
+  }
+
+  pub fn connection_rejected(&self) {
+    self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Prometheus text exposition format
+  fn render(&self, cache: Option<(usize, usize)>) -> String {
+    let mut out = String::new();
+    out.push_str(
+      "# HELP lightstatic_http_requests_total Total http requests by status and path class.\n",
+    );
+    out.push_str("# TYPE lightstatic_http_requests_total counter\n");
+    if let Some(requests) = self.requests.lock().unwrap().as_ref() {
+      let mut requests: Vec<_> = requests.iter().collect();
+      requests.sort();
+      for ((status, class), count) in requests {
+        let _ = writeln!(
+          out,
+          "lightstatic_http_requests_total{{status=\"{}\",class=\"{}\"}} {}",
+          status, class, count
+        );
+      }
+    }
+    counter(
+      &mut out,
+      "lightstatic_http_response_bytes_total",
+      "Total bytes of response bodies with known length.",
+      self.response_bytes.load(Ordering::Relaxed),
+    );
+
+    out.push_str("# HELP lightstatic_http_request_duration_seconds Http request latency.\n");
+    out.push_str("# TYPE lightstatic_http_request_duration_seconds histogram\n");
+    for (idx, le) in DURATION_BUCKETS.iter().enumerate() {
+      let _ = writeln!(
+        out,
+        "lightstatic_http_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+        le,
+        self.duration_buckets[idx].load(Ordering::Relaxed)
+      );
+    }
+    let count = self.duration_count.load(Ordering::Relaxed);
+    let _ = writeln!(
+      out,
+      "lightstatic_http_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+      count
+    );
+    let _ = writeln!(
+      out,
+      "lightstatic_http_request_duration_seconds_sum {}",
+      self.duration_sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+    );
+    let _ = writeln!(
+      out,
+      "lightstatic_http_request_duration_seconds_count {}",
+      count
+    );
+
+    gauge(
+      &mut out,
+      "lightstatic_open_connections",
+      "Currently open client connections.",
+      self.open_connections.load(Ordering::Relaxed),
+    );
+    counter(
+      &mut out,
+      "lightstatic_rejected_connections_total",
+      "Connections closed on accept because of --max-connections.",
+      self.rejected_connections.load(Ordering::Relaxed),
+    );
+
+    if let Some((files, bytes)) = cache {
+      counter(
+        &mut out,
+        "lightstatic_cache_hits_total",
+        "Requests served from the in-memory file cache.",
+        self.cache_hits.load(Ordering::Relaxed),
+      );
+      counter(
+        &mut out,
+        "lightstatic_cache_misses_total",
+        "Requests not found in the in-memory file cache.",
+        self.cache_misses.load(Ordering::Relaxed),
+      );
+      gauge(
+        &mut out,
+        "lightstatic_cache_files",
+        "Files in the in-memory file cache.",
+        files,
+      );
+      gauge(
+        &mut out,
+        "lightstatic_cache_bytes",
+        "Bytes held by the in-memory file cache.",
+        bytes,
+      );
+      counter(
+        &mut out,
+        "lightstatic_cache_refreshes_total",
+        "Completed refreshes of the in-memory file cache.",
+        self.cache_refreshes.load(Ordering::Relaxed),
+      );
+      counter(
+        &mut out,
+        "lightstatic_cache_refresh_seconds_total",
+        "Total time spent refreshing the in-memory file cache.",
+        self.cache_refresh_micros.load(Ordering::Relaxed) as f64 / 1e6,
+      );
+    }
+    out
+  }
+}
+
+fn counter<T: std::fmt::Display>(out: &mut String, name: &str, help: &str, value: T) {
+  let _ = write!(
+    out,
+    "# HELP {0} {1}\n# TYPE {0} counter\n{0} {2}\n",
+    name, help, value
+  );
+}
+
+fn gauge<T: std::fmt::Display>(out: &mut String, name: &str, help: &str, value: T) {
+  let _ = write!(
+    out,
+    "# HELP {0} {1}\n# TYPE {0} gauge\n{0} {2}\n",
+    name, help, value
+  );
+}
+
+/// 按扩展名对路径分类，避免直接用路径作为 label 导致时间序列数量失控
+fn path_class(path: &str) -> &'static str {
+  let file_name = path.rsplit('/').next().unwrap_or("");
+  let Some((_, ext)) = file_name.rsplit_once('.') else {
+    return if file_name.is_empty() {
+      "directory"
+    } else {
+      "route"
+    };
+  };
+  match ext.to_ascii_lowercase().as_str() {
+    "html" | "htm" => "html",
+    "js" | "mjs" | "cjs" | "wasm" => "script",
+    "css" => "style",
+    "png" | "jpg" | "jpeg" | "gif" | "svg" | "ico" | "webp" | "avif" | "bmp" => "image",
+    "woff" | "woff2" | "ttf" | "otf" | "eot" => "font",
+    "mp4" | "webm" | "mp3" | "ogg" | "wav" | "m3u8" | "ts" => "media",
+    "json" | "xml" | "txt" | "map" | "webmanifest" => "data",
+    _ => "other",
+  }
+}
+
+/// --metrics-addr 对应服务的处理函数，任何路径都返回 metrics
+pub fn handle_metrics<'a>(
+  req: Request<AppConfig>,
+  _: Next<'a, AppConfig>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+  Box::pin(async move {
+    let cache = req.state().cache_stats().await;
+    Ok(
+      Response::builder(200)
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render(cache))
+        .build(),
+    )
+  })
+}
+
+#[test]
+fn test_metrics() {
+  let metrics = Metrics::new();
+  metrics.record_request("/assets/app.js", 200, Some(100), Duration::from_millis(3));
+  metrics.record_request("/about", 200, None, Duration::from_millis(30));
+  metrics.record_request("/assets/app.js", 304, None, Duration::from_millis(3));
+  metrics.record_cache(true);
+  let text = metrics.render(Some((2, 300)));
+  assert!(text.contains("lightstatic_http_requests_total{status=\"200\",class=\"script\"} 1\n"));
+  assert!(text.contains("lightstatic_http_requests_total{status=\"200\",class=\"route\"} 1\n"));
+  assert!(text.contains("lightstatic_http_response_bytes_total 100\n"));
+  assert!(text.contains("lightstatic_http_request_duration_seconds_bucket{le=\"0.005\"} 2\n"));
+  assert!(text.contains("lightstatic_http_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+  assert!(text.contains("lightstatic_cache_hits_total 1\n"));
+  assert!(text.contains("lightstatic_cache_files 2\n"));
+  assert!(!Metrics::new().render(None).contains("lightstatic_cache"));
+}
@@ -0,0 +1,561 @@
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use ahash::AHashMap;
+use async_std::fs::File;
+use async_std::io::ReadExt;
+use async_std::path::{Path, PathBuf};
+use http_types::mime::BYTE_STREAM;
+use http_types::Mime;
+use log::error;
+
+/// 内置的扩展名和 MIME 类型，扩展名均为小写
+const BUILTIN_TYPES: &[(&str, &str)] = &[
+  // text
+  ("html", "text/html"),
+  ("htm", "text/html"),
+  ("shtml", "text/html"),
+  ("xhtml", "application/xhtml+xml"),
+  ("css", "text/css"),
+  ("txt", "text/plain"),
+  ("text", "text/plain"),
+  ("log", "text/plain"),
+  ("md", "text/markdown"),
+  ("markdown", "text/markdown"),
+  ("csv", "text/csv"),
+  ("tsv", "text/tab-separated-values"),
+  ("ics", "text/calendar"),
+  ("vtt", "text/vtt"),
+  ("xml", "application/xml"),
+  ("rss", "application/rss+xml"),
+  ("atom", "application/atom+xml"),
+  ("yaml", "application/yaml"),
+  ("yml", "application/yaml"),
+  ("toml", "application/toml"),
+  // script & data
+  ("js", "application/javascript"),
+  ("mjs", "application/javascript"),
+  ("cjs", "application/javascript"),
+  ("jsonp", "application/javascript"),
+  // TypeScript/JSX 源码浏览器不能直接执行，按纯文本返回
+  ("tsx", "text/plain"),
+  ("jsx", "text/plain"),
+  ("mts", "text/plain"),
+  ("cts", "text/plain"),
+  ("json", "application/json"),
+  ("map", "application/json"),
+  ("jsonld", "application/ld+json"),
+  ("webmanifest", "application/manifest+json"),
+  ("wasm", "application/wasm"),
+  // image
+  ("png", "image/png"),
+  ("apng", "image/apng"),
+  ("jpg", "image/jpeg"),
+  ("jpeg", "image/jpeg"),
+  ("jfif", "image/jpeg"),
+  ("gif", "image/gif"),
+  ("webp", "image/webp"),
+  ("avif", "image/avif"),
+  ("jxl", "image/jxl"),
+  ("heic", "image/heic"),
+  ("heif", "image/heif"),
+  ("bmp", "image/bmp"),
+  ("ico", "image/x-icon"),
+  ("icon", "image/x-icon"),
+  ("cur", "image/x-icon"),
+  ("svg", "image/svg+xml"),
+  ("tif", "image/tiff"),
+  ("tiff", "image/tiff"),
+  // font
+  ("woff", "font/woff"),
+  ("woff2", "font/woff2"),
+  ("ttf", "font/ttf"),
+  ("otf", "font/otf"),
+  ("ttc", "font/collection"),
+  ("eot", "application/vnd.ms-fontobject"),
+  // audio
+  ("mp3", "audio/mpeg"),
+  ("ogg", "audio/ogg"),
+  ("oga", "audio/ogg"),
+  ("opus", "audio/opus"),
+  ("wav", "audio/wav"),
+  ("flac", "audio/flac"),
+  ("aac", "audio/aac"),
+  ("m4a", "audio/mp4"),
+  ("weba", "audio/webm"),
+  ("mid", "audio/midi"),
+  ("midi", "audio/midi"),
+  // video
+  ("mp4", "video/mp4"),
+  ("m4v", "video/mp4"),
+  ("webm", "video/webm"),
+  ("ogv", "video/ogg"),
+  ("mov", "video/quicktime"),
+  ("avi", "video/x-msvideo"),
+  ("mkv", "video/x-matroska"),
+  ("3gp", "video/3gpp"),
+  ("flv", "video/x-flv"),
+  // HLS 分片，和 TypeScript 源码冲突，见 SNIFF_EXTS
+  ("ts", "video/mp2t"),
+  ("m2ts", "video/mp2t"),
+  ("m3u8", "application/vnd.apple.mpegurl"),
+  ("mpd", "application/dash+xml"),
+  // document
+  ("pdf", "application/pdf"),
+  ("rtf", "application/rtf"),
+  ("epub", "application/epub+zip"),
+  ("doc", "application/msword"),
+  (
+    "docx",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+  ),
+  ("xls", "application/vnd.ms-excel"),
+  (
+    "xlsx",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+  ),
+  ("ppt", "application/vnd.ms-powerpoint"),
+  (
+    "pptx",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+  ),
+  ("odt", "application/vnd.oasis.opendocument.text"),
+  ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+  ("odp", "application/vnd.oasis.opendocument.presentation"),
+  // archive
+  ("zip", "application/zip"),
+  ("gz", "application/gzip"),
+  ("tgz", "application/gzip"),
+  ("tar", "application/x-tar"),
+  ("bz2", "application/x-bzip2"),
+  ("xz", "application/x-xz"),
+  ("zst", "application/zstd"),
+  ("7z", "application/x-7z-compressed"),
+  ("rar", "application/vnd.rar"),
+  ("jar", "application/java-archive"),
+  ("apk", "application/vnd.android.package-archive"),
+  // other
+  ("glb", "model/gltf-binary"),
+  ("gltf", "model/gltf+json"),
+  ("crt", "application/x-x509-ca-cert"),
+  ("pem", "application/x-pem-file"),
+];
+
+/// 不是 text/* 但内容同样是文本、需要带上 charset 的类型
+const UTF8_TYPES: &[&str] = &[
+  "application/javascript",
+  "application/json",
+  "application/ld+json",
+  "application/manifest+json",
+  "application/xml",
+  "application/xhtml+xml",
+  "application/rss+xml",
+  "application/atom+xml",
+  "application/yaml",
+  "application/toml",
+  "image/svg+xml",
+];
+
+/// 内置类型有歧义、需要结合内容判断的扩展名（被 --mime 或 --mime-file 覆盖后不再判断）
+const SNIFF_EXTS: &[&str] = &["ts"];
+
+/// 每个目录下可以放一个该文件（Apache mime.types 格式），覆盖该目录及子目录下文件的类型
+pub const DIR_MIME_FILE: &str = ".mime.types";
+
+/// DIR_MIME_FILE 是服务端的配置，不能被下载、列出或缓存
+pub fn is_dir_mime_file(path: &Path) -> bool {
+  path.file_name().is_some_and(|name| name == DIR_MIME_FILE)
+}
+
+/// 根据内容判断类型时读取的文件开头长度
+pub const SNIFF_LEN: usize = 512;
+
+/// MPEG-TS 包长度，每个包以同步字节 0x47 开头
+const TS_PACKET_LEN: usize = 188;
+
+/// 根据文件开头的 magic bytes 判断常见的二进制格式，不含控制字符的 UTF-8 内容视为纯文本。
+/// 不会判断出 html 之类浏览器会执行的类型。
+pub fn sniff(head: &[u8]) -> Option<Mime> {
+  let head = &head[..head.len().min(SNIFF_LEN)];
+  let starts = |magic: &[u8]| head.starts_with(magic);
+  let at = |offset: usize, magic: &[u8]| head.get(offset..).is_some_and(|b| b.starts_with(magic));
+  let mime = if starts(b"\x89PNG\r\n\x1a\n") {
+    "image/png"
+  } else if starts(b"\xff\xd8\xff") {
+    "image/jpeg"
+  } else if starts(b"GIF87a") || starts(b"GIF89a") {
+    "image/gif"
+  } else if starts(b"RIFF") && at(8, b"WEBP") {
+    "image/webp"
+  } else if starts(b"RIFF") && at(8, b"WAVE") {
+    "audio/wav"
+  } else if starts(b"RIFF") && at(8, b"AVI ") {
+    "video/x-msvideo"
+  } else if starts(b"BM") && at(6, b"\0\0\0\0") {
+    "image/bmp"
+  } else if starts(b"\x00\x00\x01\x00") {
+    "image/x-icon"
+  } else if at(4, b"ftyp") {
+    match head.get(8..12) {
+      Some(b"avif") | Some(b"avis") => "image/avif",
+      Some(b"heic") | Some(b"heix") | Some(b"mif1") => "image/heic",
+      Some(b"M4A ") => "audio/mp4",
+      Some(b"qt  ") => "video/quicktime",
+      _ => "video/mp4",
+    }
+  } else if starts(b"\x1a\x45\xdf\xa3") {
+    "video/webm"
+  } else if starts(b"OggS") {
+    "audio/ogg"
+  } else if starts(b"fLaC") {
+    "audio/flac"
+  } else if starts(b"ID3") || starts(b"\xff\xfb") || starts(b"\xff\xf3") {
+    "audio/mpeg"
+  } else if starts(b"%PDF-") {
+    "application/pdf"
+  } else if starts(b"PK\x03\x04") {
+    "application/zip"
+  } else if starts(b"\x1f\x8b") {
+    "application/gzip"
+  } else if starts(b"7z\xbc\xaf\x27\x1c") {
+    "application/x-7z-compressed"
+  } else if starts(b"\x28\xb5\x2f\xfd") {
+    "application/zstd"
+  } else if starts(b"\xfd7zXZ\x00") {
+    "application/x-xz"
+  } else if starts(b"\x00asm") {
+    "application/wasm"
+  } else if starts(b"wOFF") {
+    "font/woff"
+  } else if starts(b"wOF2") {
+    "font/woff2"
+  } else if starts(b"OTTO") {
+    "font/otf"
+  } else if starts(b"\x00\x01\x00\x00\x00") {
+    "font/ttf"
+  } else if starts(&[0x47]) && (head.len() <= TS_PACKET_LEN || at(TS_PACKET_LEN, &[0x47])) {
+    "video/mp2t"
+  } else if is_text(head) {
+    "text/plain"
+  } else {
+    return None;
+  };
+  Some(with_charset(Mime::from_str(mime).unwrap()))
+}
+
+fn is_text(head: &[u8]) -> bool {
+  if head.is_empty() {
+    return false;
+  }
+  // 截断位置可能在多字节字符中间
+  let valid = match std::str::from_utf8(head) {
+    Ok(_) => true,
+    Err(err) => err.error_len().is_none() && head.len() == SNIFF_LEN,
+  };
+  valid
+    && !head
+      .iter()
+      .any(|b| b.is_ascii_control() && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c))
+}
+
+/// 读取文件开头判断类型
+pub async fn sniff_file(file: &Path) -> Option<Mime> {
+  let mut head = Vec::with_capacity(SNIFF_LEN);
+  let file = File::open(file).await.ok()?;
+  file
+    .take(SNIFF_LEN as u64)
+    .read_to_end(&mut head)
+    .await
+    .ok()?;
+  sniff(&head)
+}
+
+/// --mime 的参数，如 "glb=model/gltf-binary"
+#[derive(Clone, Debug)]
+pub struct MimeOverride {
+  pub ext: String,
+  pub mime: Mime,
+}
+
+impl FromStr for MimeOverride {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let Some((ext, mime)) = s.split_once('=') else {
+      return Err(format!(
+        "invalid mime override \"{}\", expect \"EXT=TYPE\"",
+        s
+      ));
+    };
+    let ext = ext.trim().trim_start_matches('.');
+    if ext.is_empty() {
+      return Err(format!("missing extension in \"{}\"", s));
+    }
+    Ok(MimeOverride {
+      ext: ext.to_ascii_lowercase(),
+      mime: parse_mime(mime.trim())?,
+    })
+  }
+}
+
+fn parse_mime(s: &str) -> Result<Mime, String> {
+  Mime::from_str(s).map_err(|_| format!("invalid mime type \"{}\"", s))
+}
+
+/// 文本类型补充 "charset=utf-8"，已指定 charset 的保持不变
+fn with_charset(mime: Mime) -> Mime {
+  let is_text = mime.basetype() == "text" || UTF8_TYPES.contains(&mime.essence());
+  if !is_text || mime.param("charset").is_some() {
+    return mime;
+  }
+  Mime::from_str(&format!("{}; charset=utf-8", mime)).unwrap_or(mime)
+}
+
+type TypeMap = AHashMap<String, Mime>;
+
+/// 扩展名到 MIME 类型的映射，内置类型可以被 --mime-file 和 --mime 覆盖（后者优先），
+/// 目录下的 .mime.types 优先级最高。
+pub struct MimeTable {
+  types: TypeMap,
+  sniff_exts: Vec<String>,
+  /// 已读取过的目录覆盖，None 表示该目录没有 .mime.types，refresh 时清空
+  dirs: Mutex<AHashMap<PathBuf, Option<Arc<TypeMap>>>>,
+}
+
+impl Default for MimeTable {
+  fn default() -> Self {
+    let types = BUILTIN_TYPES
+      .iter()
+      .map(|(ext, mime)| (ext.to_string(), with_charset(Mime::from_str(mime).unwrap())))
+      .collect();
+    MimeTable {
+      types,
+      sniff_exts: SNIFF_EXTS.iter().map(|ext| ext.to_string()).collect(),
+      dirs: Mutex::new(AHashMap::new()),
+    }
+  }
+}
+
+impl MimeTable {
+  pub fn load(overrides: &[MimeOverride], mime_file: Option<&Path>) -> io::Result<Self> {
+    let mut table = MimeTable::default();
+    if let Some(mime_file) = mime_file {
+      let content = std::fs::read_to_string(mime_file)?;
+      let types = parse_mime_types(&content).map_err(|err| {
+        io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("{}: {}", mime_file.display(), err),
+        )
+      })?;
+      table.extend(types);
+    }
+    table.extend(
+      overrides
+        .iter()
+        .map(|item| (item.ext.clone(), with_charset(item.mime.clone()))),
+    );
+    Ok(table)
+  }
+
+  /// 明确指定的类型覆盖内置类型，不再根据内容判断
+  fn extend(&mut self, types: impl IntoIterator<Item = (String, Mime)>) {
+    for (ext, mime) in types {
+      self.sniff_exts.retain(|e| *e != ext);
+      self.types.insert(ext, mime);
+    }
+  }
+
+  fn get(&self, ext: &str) -> Option<&Mime> {
+    self
+      .types
+      .get(ext)
+      .or_else(|| self.types.get(&ext.to_ascii_lowercase()))
+  }
+
+  /// 确定 root 下文件的类型：目录覆盖 > 扩展名 > sniffed（扩展名未知或有歧义时才会 await）
+  pub async fn detect(
+    &self,
+    root: &Path,
+    file: &Path,
+    sniffed: impl Future<Output = Option<Mime>>,
+  ) -> Mime {
+    let ext = file
+      .extension()
+      .and_then(|p| p.to_str())
+      .map(|ext| ext.to_ascii_lowercase());
+    if let Some(ext) = &ext {
+      if let Some(mime) = self.dir_override(root, file, ext).await {
+        return mime;
+      }
+    }
+    let known = ext.as_deref().and_then(|ext| self.get(ext));
+    match (&ext, known) {
+      (Some(ext), Some(mime)) if !self.sniff_exts.contains(ext) => mime.clone(),
+      _ => sniffed
+        .await
+        .or_else(|| known.cloned())
+        .unwrap_or(BYTE_STREAM),
+    }
+  }
+
+  /// 从文件所在目录开始向上查找 .mime.types 直到 root，离文件最近的优先
+  async fn dir_override(&self, root: &Path, file: &Path, ext: &str) -> Option<Mime> {
+    let mut dir = file.parent();
+    while let Some(current) = dir {
+      if !current.starts_with(root) {
+        break;
+      }
+      if let Some(mime) = self
+        .dir_types(current)
+        .await
+        .and_then(|t| t.get(ext).cloned())
+      {
+        return Some(mime);
+      }
+      dir = current.parent();
+    }
+    None
+  }
+
+  async fn dir_types(&self, dir: &Path) -> Option<Arc<TypeMap>> {
+    if let Some(types) = self.dirs.lock().unwrap().get(dir) {
+      return types.clone();
+    }
+    let mime_file = dir.join(DIR_MIME_FILE);
+    let types = match async_std::fs::read_to_string(&mime_file).await {
+      Ok(content) => match parse_mime_types(&content) {
+        Ok(types) => Some(Arc::new(types.collect())),
+        Err(err) => {
+          error!("Ignore invalid {}: {}", mime_file.display(), err);
+          None
+        }
+      },
+      Err(_) => None,
+    };
+    self
+      .dirs
+      .lock()
+      .unwrap()
+      .insert(dir.to_path_buf(), types.clone());
+    types
+  }
+
+  /// 目录下的 .mime.types 有修改时需要调用（SIGHUP 或管理接口刷新缓存）
+  pub fn clear_dir_cache(&self) {
+    self.dirs.lock().unwrap().clear();
+  }
+}
+
+/// Apache mime.types 格式，每行 "type ext1 ext2 ..."，"#" 开头为注释
+fn parse_mime_types(content: &str) -> Result<impl Iterator<Item = (String, Mime)>, String> {
+  let mut types = Vec::new();
+  for (idx, line) in content.lines().enumerate() {
+    let line = line.split('#').next().unwrap_or("");
+    let mut parts = line.split_whitespace();
+    let Some(mime) = parts.next() else {
+      continue;
+    };
+    let mime = with_charset(parse_mime(mime).map_err(|err| format!("line {}: {}", idx + 1, err))?);
+    types.extend(parts.map(|ext| (ext.to_ascii_lowercase(), mime.clone())));
+  }
+  Ok(types.into_iter())
+}
+
+#[test]
+fn test_mime_table() {
+  let overrides: Vec<MimeOverride> = ["glb=model/x-custom", ".Log=text/x-log"]
+    .iter()
+    .map(|s| s.parse().unwrap())
+    .collect();
+  let mut table = MimeTable::load(&overrides, None).unwrap();
+  assert_eq!("font/woff2", table.get("woff2").unwrap().to_string());
+  assert_eq!("font/woff2", table.get("WOFF2").unwrap().to_string());
+  assert_eq!(
+    "text/css;charset=utf-8",
+    table.get("css").unwrap().to_string().replace(' ', "")
+  );
+  assert_eq!(
+    "application/javascript;charset=utf-8",
+    table.get("JS").unwrap().to_string().replace(' ', "")
+  );
+  assert_eq!("image/png", table.get("png").unwrap().to_string());
+  assert!(table.get("unknown").is_none());
+  assert_eq!("model/x-custom", table.get("glb").unwrap().to_string());
+  assert_eq!(
+    "text/x-log;charset=utf-8",
+    table.get("log").unwrap().to_string().replace(' ', "")
+  );
+  table.extend(parse_mime_types("# comment\napplication/x-foo foo FOO2\n\n").unwrap());
+  assert_eq!("application/x-foo", table.get("foo2").unwrap().to_string());
+  assert!("noequal".parse::<MimeOverride>().is_err());
+  assert!("x=not a mime".parse::<MimeOverride>().is_err());
+}
+
+#[test]
+fn test_sniff() {
+  let mime = |head: &[u8]| sniff(head).map(|m| m.essence().to_string());
+  assert_eq!(
+    Some("image/png".into()),
+    mime(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")
+  );
+  assert_eq!(Some("image/webp".into()), mime(b"RIFF\0\0\0\0WEBPVP8 "));
+  assert_eq!(
+    Some("video/mp4".into()),
+    mime(b"\0\0\0\x20ftypisom\0\0\x02\0")
+  );
+  assert_eq!(Some("application/pdf".into()), mime(b"%PDF-1.7"));
+  let mut ts = vec![0u8; TS_PACKET_LEN * 2];
+  ts[0] = 0x47;
+  ts[TS_PACKET_LEN] = 0x47;
+  assert_eq!(Some("video/mp2t".into()), mime(&ts));
+  ts[TS_PACKET_LEN] = 0;
+  assert_eq!(None, mime(&ts));
+  assert_eq!(
+    Some("text/plain".into()),
+    mime("const a: 数字 = 1;\n".as_bytes())
+  );
+  assert_eq!(None, mime(b""));
+  assert_eq!(None, mime(b"\x01\x02\x03"));
+}
+
+#[async_std::test]
+async fn test_detect() {
+  let root = std::env::temp_dir().join(format!("lightstatic-mime-{}", std::process::id()));
+  let hls = root.join("hls");
+  std::fs::create_dir_all(hls.join("720p")).unwrap();
+  std::fs::write(hls.join(DIR_MIME_FILE), "video/x-custom ts\n").unwrap();
+  let root = PathBuf::from(root);
+  let table = MimeTable::default();
+  let text = async { Some(with_charset(Mime::from_str("text/plain").unwrap())) };
+  let detect = |file: &str, sniffed: Option<&str>| {
+    let sniffed = sniffed.map(|s| Mime::from_str(s).unwrap());
+    let file = root.join(file);
+    let table = &table;
+    let root = &root;
+    async move {
+      table
+        .detect(root, &file, async { sniffed })
+        .await
+        .essence()
+        .to_string()
+    }
+  };
+  assert_eq!("video/mp2t", detect("a.ts", None).await);
+  assert_eq!(
+    "text/plain",
+    table
+      .detect(&root, &root.join("a.ts"), text)
+      .await
+      .essence()
+  );
+  assert_eq!(
+    "video/x-custom",
+    detect("hls/720p/0.ts", Some("text/plain")).await
+  );
+  assert_eq!("image/png", detect("hls/a.png", Some("text/plain")).await);
+  assert_eq!("image/png", detect("noext", Some("image/png")).await);
+  assert_eq!("application/octet-stream", detect("noext", None).await);
+  std::fs::remove_dir_all(&root).unwrap();
+}
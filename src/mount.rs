@@ -0,0 +1,209 @@
+use std::io;
+use std::str::FromStr;
+
+use async_std::path::{Path, PathBuf};
+use async_std::sync::RwLock;
+use http_types::headers::{HeaderName, HeaderValue};
+use regex::Regex;
+
+use crate::store::{init_cache_store, FileCacheStore};
+use crate::util::normalize_base_href;
+
+/// --mount 的参数，如 "/static=./build/static,cache,header=Cache-Control:max-age=3600"。
+/// header 的值可能带逗号，所以 header 选项必须放在最后，多个 header 以 ",header=" 分隔。
+#[derive(Clone, Debug, PartialEq)]
+pub struct MountSpec {
+  /// url 前缀，统一为 "/xxx/"，根目录为 "/"
+  pub prefix: String,
+  pub dir: String,
+  pub html5: bool,
+  pub cache: bool,
+  pub listing: bool,
+  /// html5 模式下找不到文件时返回的页面
+  pub index: String,
+  pub headers: Vec<(String, String)>,
+}
+
+impl MountSpec {
+  pub fn new(prefix: &str, dir: &str) -> Self {
+    MountSpec {
+      prefix: normalize_base_href(prefix).unwrap_or_else(|| "/".to_string()),
+      dir: dir.to_string(),
+      html5: false,
+      cache: false,
+      listing: false,
+      index: "index.html".to_string(),
+      headers: Vec::new(),
+    }
+  }
+}
+
+impl FromStr for MountSpec {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let Some((prefix, rest)) = s.split_once('=') else {
+      return Err(format!(
+        "invalid mount \"{}\", expect \"PREFIX=DIR[,OPTION...]\"",
+        s
+      ));
+    };
+    let (rest, headers) = match rest.split_once(",header=") {
+      Some((rest, headers)) => (rest, Some(headers)),
+      None => (rest, None),
+    };
+    let mut parts = rest.split(',');
+    let dir = parts.next().unwrap_or("").trim();
+    if dir.is_empty() {
+      return Err(format!("missing directory in mount \"{}\"", s));
+    }
+    let mut spec = MountSpec::new(prefix.trim(), dir);
+    for option in parts.map(str::trim) {
+      match option.split_once('=') {
+        None if option == "html5" => spec.html5 = true,
+        None if option == "cache" => spec.cache = true,
+        None if option == "listing" => spec.listing = true,
+        Some(("index", index)) if !index.is_empty() => spec.index = index.to_string(),
+        _ => {
+          return Err(format!(
+            "unknown mount option \"{}\", expect html5, cache, listing, index=FILE or header=NAME:VALUE",
+            option
+          ))
+        }
+      }
+    }
+    for header in headers.into_iter().flat_map(|h| h.split(",header=")) {
+      spec.headers.push(parse_header(header)?);
+    }
+    Ok(spec)
+  }
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+  let invalid = || format!("invalid header \"{}\", expect \"NAME:VALUE\"", s);
+  let (name, value) = s.split_once(':').ok_or_else(invalid)?;
+  let (name, value) = (name.trim(), value.trim());
+  if HeaderName::from_str(name).is_err() || HeaderValue::from_str(value).is_err() {
+    return Err(invalid());
+  }
+  Ok((name.to_string(), value.to_string()))
+}
+
+/// 挂载到某个 url 前缀下的目录
+pub struct Mount {
+  pub prefix: String,
+  pub root_dir: PathBuf,
+  pub index_href: PathBuf,
+  pub html5: bool,
+  /// 请求目录时返回文件列表，否则返回 404
+  pub listing: bool,
+  /// 添加到该目录下所有成功响应的 header
+  pub headers: Vec<(String, String)>,
+  pub cache_store: Option<RwLock<FileCacheStore>>,
+}
+
+impl Mount {
+  /// 请求路径在该挂载目录下的相对路径，不属于该目录时返回 None
+  pub fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+    if !Path::new(path).starts_with(&self.prefix) {
+      return None;
+    }
+    Some(path.get(self.prefix.len()..).unwrap_or(""))
+  }
+}
+
+/// 读取挂载目录（开启缓存时读入内存），按前缀从长到短排序，前缀重复时返回错误
+pub async fn load_mounts(
+  specs: Vec<MountSpec>,
+  regex_immutable: Option<&Regex>,
+) -> io::Result<Vec<Mount>> {
+  let mut mounts: Vec<Mount> = Vec::with_capacity(specs.len());
+  for spec in specs {
+    if mounts.iter().any(|mount| mount.prefix == spec.prefix) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("directory mounted on {} more than once", spec.prefix),
+      ));
+    }
+    let root_dir = PathBuf::from(&spec.dir);
+    let index_href = root_dir.join(&spec.index);
+    let cache_store = if spec.cache {
+      let store = init_cache_store(
+        index_href.clone(),
+        root_dir.clone(),
+        regex_immutable.cloned(),
+      )
+      .await?;
+      Some(RwLock::new(store))
+    } else {
+      None
+    };
+    mounts.push(Mount {
+      prefix: spec.prefix,
+      root_dir,
+      index_href,
+      html5: spec.html5,
+      listing: spec.listing,
+      headers: spec.headers,
+      cache_store,
+    });
+  }
+  mounts.sort_by_key(|mount| std::cmp::Reverse(mount.prefix.len()));
+  Ok(mounts)
+}
+
+#[test]
+fn test_mount_spec() {
+  let spec: MountSpec = "static=./build/static".parse().unwrap();
+  assert_eq!("/static/", spec.prefix);
+  assert_eq!("./build/static", spec.dir);
+  assert!(!spec.html5 && !spec.cache && !spec.listing);
+
+  let spec: MountSpec = "/=./public,html5,cache,listing,index=app.html"
+    .parse()
+    .unwrap();
+  assert_eq!("/", spec.prefix);
+  assert!(spec.html5 && spec.cache && spec.listing);
+  assert_eq!("app.html", spec.index);
+
+  let spec: MountSpec =
+    "/uploads=/var/uploads,header=Cache-Control: public, max-age=60,header=X-Frame-Options:DENY"
+      .parse()
+      .unwrap();
+  assert_eq!(
+    vec![
+      (
+        "Cache-Control".to_string(),
+        "public, max-age=60".to_string()
+      ),
+      ("X-Frame-Options".to_string(), "DENY".to_string()),
+    ],
+    spec.headers
+  );
+
+  assert!("/static".parse::<MountSpec>().is_err());
+  assert!("/static=".parse::<MountSpec>().is_err());
+  assert!("/static=./dist,gzip".parse::<MountSpec>().is_err());
+  assert!("/static=./dist,header=nocolon"
+    .parse::<MountSpec>()
+    .is_err());
+}
+
+#[test]
+fn test_mount_relative() {
+  let mount = |prefix: &str| Mount {
+    prefix: prefix.to_string(),
+    root_dir: PathBuf::from("/srv"),
+    index_href: PathBuf::from("/srv/index.html"),
+    html5: false,
+    listing: false,
+    headers: Vec::new(),
+    cache_store: None,
+  };
+  assert_eq!(Some("a/b.js"), mount("/").relative("/a/b.js"));
+  assert_eq!(Some("b.js"), mount("/static/").relative("/static/b.js"));
+  assert_eq!(Some(""), mount("/static/").relative("/static"));
+  assert_eq!(Some(""), mount("/static/").relative("/static/"));
+  assert_eq!(None, mount("/static/").relative("/staticx/b.js"));
+  assert_eq!(None, mount("/static/").relative("/"));
+}
@@ -1,111 +1,247 @@
-use std::thread::sleep;
-use std::time::Duration;
+use std::ffi::OsString;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{
   env::temp_dir,
   fs::{self, read_to_string, File},
-  io::{self, Write},
+  io::{self, ErrorKind, Write},
   process,
 };
 
-use libc::{kill, pid_t, SIGHUP, SIGTERM};
+use libc::{kill, pid_t, SIGHUP, SIGTERM, SIGUSR2};
 
-use crate::{error, info};
+use log::{error, info, warn};
 
-const PID_FILE: &str = "lightstatic.pid";
+use crate::args::Args;
 
-pub fn handle_arg_signal(action: &str) -> bool {
-  // if let Some(action) = &args.signal {
+/// 未指定 --pid-file 时，每个实例在该目录下写一个自己的 pid 文件：有 --name 时为 `{name}.pid`，否则为 `{pid}.pid`
+const PID_DIR: &str = "lightstatic";
+
+/// 当前实例的 pid 文件。运行期间一直持有文件的排他锁，锁能被别人拿到就说明进程已经不在了。
+static PID_FILE: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+fn pid_dir() -> PathBuf {
+  temp_dir().join(PID_DIR)
+}
+
+pub fn instance_pid_file(args: &Args) -> PathBuf {
+  match (&args.pid_file, &args.name) {
+    (Some(pid_file), _) => PathBuf::from(pid_file),
+    (None, Some(name)) => pid_dir().join(format!("{}.pid", name)),
+    (None, None) => pid_dir().join(format!("{}.pid", process::id())),
+  }
+}
+
+fn try_lock(file: &File, operation: libc::c_int) -> bool {
+  unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) == 0 }
+}
+
+pub fn handle_arg_signal(action: &str, args: &Args) -> bool {
   let sig = match action.to_lowercase().as_str() {
     "stop" => SIGTERM,
     "refresh" => SIGHUP,
+    "upgrade" => SIGUSR2,
     _ => {
-      error!("error: option --signal only accept \"stop\" or \"refresh\" action");
+      error!("error: option --signal only accept \"stop\", \"refresh\" or \"upgrade\" action");
       process::exit(-1);
     }
   };
-  // println!("{:?}", temp_dir().join(PID_FILE));
-  if let Ok(cnt) = read_to_string(temp_dir().join(PID_FILE)) {
-    let pids: Vec<_> = cnt
-      .split('\n')
-      .filter(|pid| {
-        if let Ok(pid) = pid.parse::<pid_t>() {
-          let r = unsafe { kill(pid, sig) };
-          sleep(Duration::from_secs(1)); // 目标进程退出时也会从 pid file 中删除自身 pid，所以等待 1 秒让所有目标进程处理完后再操作。
-          info!("Send signal {} to pid {}, result {}", sig, pid, r);
-          r == 0 && sig != SIGTERM
-        } else {
-          false
-        }
+  let pid_files = if args.pid_file.is_some() || args.name.is_some() {
+    vec![instance_pid_file(args)]
+  } else {
+    fs::read_dir(pid_dir())
+      .map(|entries| {
+        entries
+          .filter_map(|entry| entry.ok())
+          .map(|entry| entry.path())
+          .filter(|path| path.extension().is_some_and(|ext| ext == "pid"))
+          .collect()
       })
-      .collect();
+      .unwrap_or_default()
+  };
+  let mut signaled = 0;
+  for pid_file in pid_files.iter() {
+    match running_pid(pid_file) {
+      Ok(Some(pid)) => {
+        let r = unsafe { kill(pid, sig) };
+        info!("Send signal {} to pid {}, result {}", sig, pid, r);
+        if r == 0 {
+          signaled += 1;
+        }
+      }
+      Ok(None) => {}
+      Err(err) if err.kind() == ErrorKind::NotFound => {}
+      Err(err) => error!("failed read pid file {} due to {}", pid_file.display(), err),
+    }
+  }
+  if signaled == 0 {
+    error!("error: no lightstatic process is running.");
+    process::exit(-1);
+  }
+  true
+}
 
-    handle_pids(&pids);
-    true
-  } else {
-    error!(
-      "error: {} not found, no lightstatic process is running.",
-      PID_FILE
+/// 读取 pid 文件，进程已不存在（文件没有被锁住）时返回 None。
+/// 这里只读不删，拿到共享锁时可能有实例正在 write_pid 中等待排他锁，遗留的文件由 write_pid 清理。
+fn running_pid(pid_file: &Path) -> io::Result<Option<pid_t>> {
+  let file = File::open(pid_file)?;
+  if try_lock(&file, libc::LOCK_SH) {
+    return Ok(None);
+  }
+  let Ok(pid) = read_to_string(pid_file)?.trim().parse::<pid_t>() else {
+    warn!("Ignore invalid pid file {}", pid_file.display());
+    return Ok(None);
+  };
+  if !is_lightstatic(pid) {
+    warn!(
+      "Ignore pid file {}, process {} is not lightstatic",
+      pid_file.display(),
+      pid
     );
-    process::exit(-1);
+    return Ok(None);
   }
+  Ok(Some(pid))
 }
 
-pub fn write_pid() -> io::Result<()> {
-  let mut f = File::options()
-    .append(true)
-    .create(true)
-    .open(temp_dir().join(PID_FILE))?;
-  let mut pid = process::id().to_string();
-  if f.metadata()?.len() > 0 {
-    pid.insert(0, '\n');
+/// 通过 /proc 确认进程的可执行文件名和当前程序一致，没有 /proc 的系统（如 macos）只依赖文件锁判断
+fn is_lightstatic(pid: pid_t) -> bool {
+  let proc_dir = Path::new("/proc");
+  if !proc_dir.exists() {
+    return true;
+  }
+  match (
+    fs::read_link(proc_dir.join(pid.to_string()).join("exe")),
+    std::env::current_exe(),
+  ) {
+    (Ok(target), Ok(current)) => exe_name(&target) == exe_name(&current),
+    // 没有权限读取其它用户进程的 exe 时，无法进一步确认
+    (Err(err), _) if err.kind() == ErrorKind::PermissionDenied => true,
+    _ => false,
   }
-  f.write_all(pid.as_bytes())
 }
 
-fn handle_pids(pids: &Vec<&str>) {
-  let pid_file = temp_dir().join(PID_FILE);
-  if pids.is_empty() {
-    if pid_file.exists() {
-      // println!("remove pid file");
-      fs::remove_file(temp_dir().join(&pid_file))
-    } else {
-      Ok(())
+/// 可执行文件被替换（升级或更新软件包）后，/proc/<pid>/exe 会带上 " (deleted)" 后缀
+fn exe_name(path: &Path) -> Option<OsString> {
+  let name = path.file_name()?;
+  Some(
+    match name
+      .to_str()
+      .and_then(|name| name.strip_suffix(" (deleted)"))
+    {
+      Some(name) => name.into(),
+      None => name.to_owned(),
+    },
+  )
+}
+
+/// 删除 dir 下没有被锁住的 pid 文件（进程异常退出时遗留的）。
+/// 持有排他锁时删除，正在 write_pid 的实例会发现锁住的文件已被删除并重新创建。
+fn remove_stale_pid_files(dir: &Path, keep: &Path) {
+  let Ok(entries) = fs::read_dir(dir) else {
+    return;
+  };
+  for path in entries
+    .filter_map(|entry| entry.ok())
+    .map(|entry| entry.path())
+  {
+    if path == keep || path.extension().is_none_or(|ext| ext != "pid") {
+      continue;
     }
-  } else {
-    File::options()
+    let Ok(file) = File::open(&path) else {
+      continue;
+    };
+    if try_lock(&file, libc::LOCK_EX) {
+      info!("Remove stale pid file {}", path.display());
+      if let Err(err) = fs::remove_file(&path) {
+        warn!("failed remove pid file {} due to {}", path.display(), err);
+      }
+    }
+  }
+}
+
+/// wait 为 true 时等待文件锁（升级时旧进程退出后才会释放），否则同名实例已在运行时返回错误
+pub fn write_pid(pid_file: PathBuf, wait: bool) -> io::Result<()> {
+  if let Some(dir) = pid_file.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  let mut f = loop {
+    // 先加锁再清空内容，避免覆盖正在运行的同名实例的 pid
+    let f = File::options()
       .write(true)
-      .truncate(true)
-      .open(&pid_file)
-      .and_then(|mut f| {
-        // let x = pids.join("\n");
-        // println!("update pid file: {:?} {}", pids, x);
-        f.write_all(pids.join("\n").as_bytes())
-      })
+      .create(true)
+      .truncate(false)
+      .open(&pid_file)?;
+    let locked = if wait {
+      unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX) == 0 }
+    } else {
+      try_lock(&f, libc::LOCK_EX)
+    };
+    if !locked {
+      return Err(io::Error::new(
+        ErrorKind::AddrInUse,
+        format!(
+          "pid file {} is locked, another lightstatic instance is running",
+          pid_file.display()
+        ),
+      ));
+    }
+    // 等待期间旧进程可能已经删除了这个文件，锁住的是已删除的文件时需要重新创建
+    if fs::metadata(&pid_file).is_ok_and(|stat| stat.ino() == f.metadata().map_or(0, |s| s.ino())) {
+      break f;
+    }
+  };
+  f.set_len(0)?;
+  f.write_all(format!("{}\n", process::id()).as_bytes())?;
+  // 只清理默认目录，--pid-file 所在的目录可能有其它程序的 pid 文件
+  if pid_file.parent() == Some(pid_dir().as_path()) {
+    remove_stale_pid_files(&pid_dir(), &pid_file);
   }
-  .unwrap_or_else(|err| {
-    sleep(Duration::from_secs(2));
-    error!(
-      "failed {} pid file due to {}",
-      if !pids.is_empty() { "update" } else { "remove" },
-      err
-    )
-  })
+  PID_FILE.lock().unwrap().replace((pid_file, f));
+  Ok(())
 }
 
 pub fn remove_pid() {
-  fn remove() -> io::Result<()> {
-    let cnt = read_to_string(temp_dir().join(PID_FILE))?;
-    let pid = process::id().to_string();
-    let pids: Vec<_> = cnt
-      .split('\n')
-      .filter(|l| !l.trim().is_empty())
-      .filter(|l| !l.eq(&pid))
-      .collect();
-    // println!("will remove {} {:?}", pid, pids);
-    handle_pids(&pids);
-    Ok(())
+  if let Some((pid_file, _)) = PID_FILE.lock().unwrap().take() {
+    if let Err(err) = fs::remove_file(&pid_file) {
+      error!(
+        "failed remove pid file {} due to {}",
+        pid_file.display(),
+        err
+      );
+    }
   }
-  if let Err(err) = remove() {
-    error!("failed remove pid: {}", err);
+}
+
+#[test]
+fn test_exe_name() {
+  assert_eq!(
+    Some("lightstatic".into()),
+    exe_name(Path::new("/usr/bin/lightstatic (deleted)"))
+  );
+  assert_eq!(
+    Some("lightstatic".into()),
+    exe_name(Path::new("/usr/bin/lightstatic"))
+  );
+  assert_eq!(None, exe_name(Path::new("/")));
+}
+
+#[test]
+fn test_stale_pid_files() {
+  let dir = temp_dir().join(format!("lightstatic-pid-{}", process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let (stale, live, own) = (dir.join("1.pid"), dir.join("2.pid"), dir.join("3.pid"));
+  for path in [&stale, &live, &own] {
+    fs::write(path, "1\n").unwrap();
   }
+  let live_file = File::open(&live).unwrap();
+  assert!(try_lock(&live_file, libc::LOCK_EX));
+  // 查询不删除遗留的文件
+  assert_eq!(None, running_pid(&stale).unwrap());
+  assert!(stale.exists());
+  remove_stale_pid_files(&dir, &own);
+  assert!(!stale.exists() && live.exists() && own.exists());
+  drop(live_file);
+  fs::remove_dir_all(&dir).unwrap();
 }
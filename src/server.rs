@@ -1,18 +1,27 @@
 use async_std::path::PathBuf;
-use async_std::sync::RwLock;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::admin::serve_admin;
 use crate::config::AppConfig;
-use crate::error;
+use crate::handoff::{bind, bind_tcp, collect_inherited, has_inherited_tcp, upgrade};
+use crate::limit::ConcurrencyLimit;
+use crate::listener::{serve, ListenAddr, Listener, ServeOptions};
+use crate::log_file::reopen_log_files;
 use crate::logger::log_startup_info;
-use crate::pid::{remove_pid, write_pid};
-use crate::server_core::handle_request;
-use crate::{args, config::Config, info};
-use tide::prelude::*;
+use crate::metrics::handle_metrics;
+use crate::pid::{instance_pid_file, remove_pid, write_pid};
+use crate::server_core::{handle_https_redirect, handle_request};
+use crate::shutdown::{drain, request_shutdown};
+use crate::tls::{ensure_dev_cert, TlsStore};
+use crate::util::resolve_path;
+use crate::{args, config::ConfigBuilder};
 
-use crate::store::{init_cache_store, refresh_cache_store};
+use async_std::path::Path;
 use async_std::stream::StreamExt;
 use colored::Colorize;
+use futures::future::try_join_all;
+use log::{error, info, warn};
 use signal_hook::consts::signal::*;
 use signal_hook_async_std::Signals;
 
@@ -23,63 +32,266 @@ async fn handle_signals(mut signals: Signals, config: AppConfig) {
       SIGHUP => {
         // Reload configuration
         // Reopen the log file
-        if let Some(store) = &config.cache_store {
+        config.mime.clear_dir_cache();
+        if let Some(auth) = &config.auth {
+          auth.reload_htpasswd();
+        }
+        if let Err(err) = reopen_log_files() {
+          error!("Failed to reopen log files due to {}", err);
+        }
+        if let Some(tls) = &config.tls {
+          match tls.reload() {
+            Ok(_) => info!("TLS certificate reloaded"),
+            Err(err) => error!("Failed to reload TLS certificate due to {}", err),
+          }
+        }
+        if config.cache_stores().next().is_some() {
           info!("Start refreshing file store");
-          if let Some(size) = refresh_cache_store(store).await {
+          if let Some(size) = config.refresh_cache().await {
             info!("File store refreshed with {} files", size);
           } else {
             error!("Failed to refresh file store");
           }
         }
       }
-      SIGTERM | SIGINT | SIGQUIT => {
-        info!("{}", "lightstatic serving stopped".red());
-        remove_pid();
-        std::process::exit(0);
-      }
+      SIGTERM | SIGINT | SIGQUIT => request_shutdown(),
+      SIGUSR2 => match upgrade() {
+        Ok(pid) => info!(
+          "Started new process {}, this process will stop after it is ready",
+          pid
+        ),
+        Err(err) => error!("Failed to start new process due to {}", err),
+      },
       _ => unreachable!(),
     }
   }
 }
 
+/// 自签名证书需要覆盖的主机，和 log_startup_info 打印的访问地址保持一致。
+fn dev_cert_hosts(args: &args::Args) -> Vec<String> {
+  let mut hosts = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+  if args.host.eq("0.0.0.0") {
+    if let Ok(ip) = local_ip_address::local_ip() {
+      hosts.push(ip.to_string());
+    }
+  } else if !hosts.contains(&args.host) {
+    hosts.push(args.host.clone());
+  }
+  for spec in args.vhost.iter() {
+    let host = spec.host.to_string();
+    if !hosts.contains(&host) {
+      hosts.push(host);
+    }
+  }
+  for addr in args.listen.iter() {
+    if let ListenAddr::Tcp(addr) = addr {
+      let ip = addr.ip().to_string();
+      if !addr.ip().is_unspecified() && !hosts.contains(&ip) {
+        hosts.push(ip);
+      }
+    }
+  }
+  hosts
+}
+
+/// 处理静态文件请求的 tide app，可以用 `nest` 挂载到其它 tide 服务的路径下
+pub fn static_files(config: AppConfig) -> tide::Server<AppConfig> {
+  let mut app = tide::with_state(config);
+  app.with(handle_request);
+  app
+}
+
 pub async fn bootstrap(args: args::Args, cwd: PathBuf) -> tide::Result<()> {
-  let free_port = port_selector::select_from_given_port(args.port).unwrap();
-  let mut app_config = Config::new(&args, &cwd);
-  let file_size = if args.cache_in_memory {
-    let cache_store = init_cache_store(
-      app_config.index_href.clone(),
-      app_config.root_dir.clone(),
-      args.regex_immutable.clone(),
-    )
-    .await;
-    let file_size = cache_store.len();
-    // info!("Init file cache store with {} files", );
-    app_config.cache_store.replace(RwLock::new(cache_store));
-    Some(file_size)
-  } else {
-    None
-  };
+  let upgrade_from = collect_inherited();
+  // 最先写 pid 文件，同名实例已在运行时直接退出。升级时旧进程还持有锁，就绪后再写
+  let pid_file = instance_pid_file(&args);
+  if upgrade_from.is_none() {
+    write_pid(pid_file.clone(), false)?;
+  }
+  let mut app_config = ConfigBuilder::from_args(&args, &cwd)?.build().await?;
+  let file_size = app_config.cache_stats().await.map(|(files, _)| files);
+  if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+    let tls = TlsStore::new(
+      resolve_path(&cwd, Path::new(cert)).into(),
+      resolve_path(&cwd, Path::new(key)).into(),
+      args.http2,
+    )?;
+    app_config.tls.replace(Arc::new(tls));
+  } else if args.https_dev {
+    let (cert, key) = ensure_dev_cert(&dev_cert_hosts(&args))?;
+    app_config
+      .tls
+      .replace(Arc::new(TlsStore::new(cert, key, args.http2)?));
+  }
   let app_config = Arc::new(app_config);
-  let mut app = tide::with_state(app_config.clone());
-  app.with(handle_request);
+  let app = static_files(app_config.clone());
+
+  let mut listeners: Vec<Listener> = Vec::new();
+  if !args.listen.is_empty() {
+    for addr in args.listen.iter() {
+      listeners.push(bind(addr, args.unix_socket_mode).await?);
+    }
+  } else if has_inherited_tcp() {
+    listeners.push(
+      bind_tcp((args.host.as_str(), args.port), true)
+        .await?
+        .into(),
+    );
+  } else {
+    let free_port = if args.strict_port {
+      args.port
+    } else {
+      port_selector::select_from_given_port(args.port).unwrap()
+    };
+    if free_port != args.port {
+      warn!(
+        "Port {} is not available, listening on {} instead",
+        args.port, free_port
+      );
+    }
+    let listener = bind_tcp((args.host.as_str(), free_port), false)
+      .await
+      .map_err(|err| {
+        std::io::Error::new(
+          err.kind(),
+          format!(
+            "failed to listen on {}:{} due to {}",
+            args.host, free_port, err
+          ),
+        )
+      })?;
+    listeners.push(listener.into());
+  }
+  let addrs = listeners
+    .iter()
+    .map(|listener| listener.local_addr())
+    .collect::<std::io::Result<Vec<_>>>()?;
+  log_startup_info(&args, &addrs, file_size);
+
+  // 实际监听的端口，自动选择端口或 --port 0 时和参数不同
+  let bound_port = addrs.iter().find_map(|addr| match addr {
+    ListenAddr::Tcp(addr) => Some(addr.port()),
+    ListenAddr::Unix(_) => None,
+  });
+  if let (Some(port_file), Some(port)) = (&args.port_file, bound_port) {
+    std::fs::write(port_file, format!("{}\n", port))?;
+  }
+  if let (Some(redirect_port), Some(https_port)) = (args.http_redirect_port, bound_port) {
+    let mut redirect_app = tide::with_state(https_port);
+    redirect_app.with(handle_https_redirect);
+    let redirect_listener = bind_tcp((args.host.as_str(), redirect_port), false).await?;
+    async_std::task::spawn(serve(
+      redirect_app,
+      redirect_listener,
+      ServeOptions::default(),
+    ));
+  }
 
-  let mut listener = app.bind((&args.host, free_port)).await?;
-  for _ in listener.info().iter() {
-    log_startup_info(&args, file_size);
+  if let Some(metrics_addr) = args.metrics_addr {
+    let mut metrics_app = tide::with_state(app_config.clone());
+    metrics_app.with(handle_metrics);
+    let metrics_listener = bind_tcp(metrics_addr, false).await?;
+    info!("Metrics available on http://{}", metrics_addr);
+    async_std::task::spawn(serve(
+      metrics_app,
+      metrics_listener,
+      ServeOptions::default(),
+    ));
   }
 
-  let signals = Signals::new(&[SIGHUP, SIGTERM, SIGINT, SIGQUIT])?;
+  if let Some(admin_addr) = args.admin_addr.clone() {
+    serve_admin(admin_addr, args.admin_token.clone(), app_config.clone()).await?;
+  }
+
+  let signals = Signals::new(&[SIGHUP, SIGTERM, SIGINT, SIGQUIT, SIGUSR2])?;
   let handle = signals.handle();
 
-  let signals_task = async_std::task::spawn(handle_signals(signals, app_config));
+  let signals_task = async_std::task::spawn(handle_signals(signals, app_config.clone()));
 
-  write_pid()?;
+  if let Some(old_pid) = upgrade_from {
+    info!("Upgraded from process {}, stopping it", old_pid);
+    unsafe { libc::kill(old_pid as libc::pid_t, libc::SIGTERM) };
+    async_std::task::spawn_blocking(move || {
+      if let Err(err) = write_pid(pid_file, true) {
+        error!("Failed to write pid file due to {}", err);
+      }
+    });
+  }
   // Execute your main program logic
-  listener.accept().await?;
+  let serve_options = ServeOptions {
+    tls: app_config.tls.clone(),
+    http2: args.http2,
+    max_connections: args
+      .max_connections
+      .map(|max| Arc::new(ConcurrencyLimit::new(max.get()))),
+  };
+  try_join_all(
+    listeners
+      .into_iter()
+      .map(|listener| serve(app.clone(), listener, serve_options.clone())),
+  )
+  .await?;
 
+  // serve 只会在收到退出请求后返回，此时不再接受新连接，等待处理中的请求完成
+  drain(Duration::from_secs(args.shutdown_timeout)).await;
+  remove_pid();
   // Terminate the signal stream.
   handle.close();
   signals_task.await;
+  info!("{}", "lightstatic serving stopped".red());
 
   Ok(())
 }
+
+#[async_std::test]
+async fn test_static_files() -> tide::Result<()> {
+  use crate::config::Config;
+  use crate::mount::MountSpec;
+  let root = std::env::temp_dir().join(format!("lightstatic-lib-{}", std::process::id()));
+  std::fs::create_dir_all(root.join("docs"))?;
+  std::fs::write(root.join("index.html"), "<html>index</html>")?;
+  std::fs::write(root.join("docs/guide.html"), "<html>guide</html>")?;
+  let config = Config::builder(root.clone())
+    .html5(true)
+    .cache_in_memory(true)
+    .health_path("/healthz")
+    .ready_path("/readyz")
+    .mount(MountSpec::new(
+      "/manual",
+      &root.join("docs").to_string_lossy(),
+    ))
+    .build()
+    .await?;
+  let app = static_files(Arc::new(config));
+  let get = |path: &str| {
+    let app = app.clone();
+    let url = http_types::Url::parse("http://localhost")
+      .unwrap()
+      .join(path)
+      .unwrap();
+    async move {
+      let mut res: http_types::Response = app
+        .respond(http_types::Request::new(http_types::Method::Get, url))
+        .await
+        .unwrap();
+      (res.status() as u16, res.body_string().await.unwrap())
+    }
+  };
+  // 嵌入时不经过 bootstrap，build() 之后就绪探针也要返回 200
+  assert_eq!((200, "ready".to_string()), get("/readyz").await);
+  assert_eq!((200, "ok".to_string()), get("/healthz").await);
+  assert_eq!(
+    (200, "<html>index</html>".to_string()),
+    get("/index.html").await
+  );
+  assert_eq!(
+    (200, "<html>index</html>".to_string()),
+    get("/some/route").await
+  );
+  assert_eq!(
+    (200, "<html>guide</html>".to_string()),
+    get("/manual/guide.html").await
+  );
+  std::fs::remove_dir_all(&root)?;
+  Ok(())
+}
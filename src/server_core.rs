@@ -1,35 +1,30 @@
-use crate::config::{AppConfig, Config};
+use crate::config::AppConfig;
+use crate::fault::throttle;
 use crate::helper::{send_dir, send_file, should_send_file};
-use crate::logger::log_access;
+use crate::limit::retry_after_secs;
+use crate::logger::{log_access, AccessRecord};
+use crate::metrics::METRICS;
+use crate::mime::{is_dir_mime_file, sniff_file};
+use crate::mount::Mount;
+use crate::sign::unix_now;
 use crate::store::send_cache_file;
 use crate::util::is_empty_root_url;
 use async_std::path::Path;
+use fastrand::Rng;
+use http_types::url::Host;
+use http_types::Version;
 use std::fs::Metadata;
-use std::ops::Deref;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use std::{future::Future, pin::Pin};
 use tide::{Next, Request, Response, StatusCode};
 
-fn send(res: tide::Result, should_log_access: bool, path: &str) -> tide::Result {
-  if !should_log_access {
-    return res;
-  }
-  let code = match &res {
-    Ok(res) => res.status() as u16,
-    Err(err) => {
-      crate::error!("{}", err);
-      500
-    }
-  };
-  log_access(code, path);
-  res
-}
-
 async fn send_file_304(
   stat: &Metadata,
   req: &Request<AppConfig>,
+  root_dir: &Path,
   file_path: &Path,
-  should_log_access: bool,
-  path: &str,
   gzip: bool,
 ) -> tide::Result {
   let mtime = stat
@@ -38,100 +33,331 @@ async fn send_file_304(
     .as_secs()
     .to_string();
   if should_send_file(req, &mtime)? {
-    send(
-      send_file(file_path, stat, &mtime, gzip).await,
-      should_log_access,
-      path,
-    )
+    let mime = req
+      .state()
+      .mime
+      .detect(root_dir, file_path, sniff_file(file_path))
+      .await;
+    send_file(file_path, stat, &mtime, gzip, mime).await
   } else {
-    send(Ok(Response::new(304)), should_log_access, path)
+    Ok(Response::new(304))
   }
 }
+
 pub fn handle_request<'a>(
   req: Request<AppConfig>,
   _: Next<'a, AppConfig>,
 ) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
   Box::pin(async move {
-    let &Config {
-      delay,
-      should_log_access,
-      ref root_dir,
-      ref base_href,
-      html5,
-      ref index_href,
-      ref cache_store,
-      gzip,
-      ..
-    } = req.state().deref();
-    if delay > 0 {
-      async_std::task::sleep(std::time::Duration::from_secs(delay)).await;
-    }
-
-    let path = req.url().path();
-    let url;
-
-    if let Some(base_href) = base_href.as_ref().filter(|p| !is_empty_root_url(*p)) {
-      // println!("with base href");
-      if is_empty_root_url(path) {
-        if should_log_access {
-          log_access(200, path)
-        };
-        return Ok(Response::builder(302).header("location", base_href).build());
-      } else if !Path::new(path).starts_with(base_href) {
-        if should_log_access {
-          log_access(403, path)
-        };
-        return Ok(Response::new(StatusCode::Forbidden));
-      } else {
-        url = if path.len() > base_href.len() {
-          &path[base_href.len()..]
-        } else {
-          &path[0..0]
-        };
+    let config = req.state();
+    let forwarded_for = req.header("x-forwarded-for").map(|values| {
+      values
+        .iter()
+        .map(|value| value.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+    });
+    let peer = req.peer_addr().map(strip_port);
+    let client_ip = config.access.client_ip(
+      peer.and_then(|peer| peer.parse().ok()),
+      forwarded_for.as_deref(),
+    );
+    // 被拒绝的客户端连探针也不能访问
+    let allowed = config.access.allows(client_ip, req.url().path());
+    // 探针请求不受 --delay 影响，也不记录访问日志
+    if allowed {
+      if let Some(res) = respond_probe(&req).await {
+        return Ok(res);
       }
+    }
+    let start = Instant::now();
+    let res = if !allowed {
+      Ok(
+        Response::builder(StatusCode::Forbidden)
+          .body("forbidden")
+          .build(),
+      )
     } else {
-      url = &path[1..];
+      respond_limited(&req, client_ip).await
+    };
+    let status = match &res {
+      Ok(res) => res.status() as u16,
+      Err(err) => {
+        log::error!("{}", err);
+        500
+      }
+    };
+    let bytes = res.as_ref().ok().and_then(|res| res.len());
+    let duration = start.elapsed();
+    METRICS.record_request(req.url().path(), status, bytes, duration);
+    if config.should_log_access.load(Ordering::Relaxed) {
+      let method = req.method();
+      let version = req.version().unwrap_or(Version::Http1_1);
+      let client_ip = client_ip.map(|ip| ip.to_string());
+      let record = AccessRecord {
+        method: method.as_ref(),
+        path: req.url().path(),
+        query: req.url().query(),
+        version: version.as_ref(),
+        status,
+        bytes,
+        duration,
+        remote_addr: client_ip.as_deref().or(peer),
+        referer: req.header("referer").map(|v| v.as_str()),
+        user_agent: req.header("user-agent").map(|v| v.as_str()),
+      };
+      log_access(&config.access_log_format, &record);
     }
+    res
+  })
+}
 
-    let file_path = root_dir.join(url);
+/// --health-path 只表示进程存活；--ready-path 还要求缓存已加载完成且根目录可读
+async fn respond_probe(req: &Request<AppConfig>) -> Option<Response> {
+  let config = req.state();
+  let path = req.url().path();
+  if config.health_path.as_deref() == Some(path) {
+    return Some(Response::builder(200).body("ok").build());
+  }
+  if config.ready_path.as_deref() == Some(path) {
+    let mut ready = config.ready.load(Ordering::Relaxed);
+    for mount in config.all_mounts() {
+      ready = ready && mount.root_dir.read_dir().await.is_ok();
+    }
+    return Some(if ready {
+      Response::builder(200).body("ready").build()
+    } else {
+      Response::builder(StatusCode::ServiceUnavailable)
+        .body("not ready")
+        .build()
+    });
+  }
+  None
+}
 
-    if let Some(cache_store) = cache_store {
-      return send(
-        send_cache_file(&req, cache_store, &file_path, html5).await,
-        should_log_access,
-        path,
+/// 超过 --rate-limit 时返回 429，超过 --max-requests 时返回 503，都带上 Retry-After
+async fn respond_limited(req: &Request<AppConfig>, client_ip: Option<IpAddr>) -> tide::Result {
+  let config = req.state();
+  if let (Some(rate_limit), Some(ip)) = (&config.rate_limit, client_ip) {
+    if let Err(wait) = rate_limit.check(ip) {
+      return Ok(
+        Response::builder(StatusCode::TooManyRequests)
+          .header("retry-after", retry_after_secs(wait).to_string())
+          .body("too many requests")
+          .build(),
       );
     }
+  }
+  // 只计算处理请求的时间，不包括发送响应体
+  let _permit = match &config.max_requests {
+    Some(max_requests) => match max_requests.try_acquire() {
+      Some(permit) => Some(permit),
+      None => {
+        return Ok(
+          Response::builder(StatusCode::ServiceUnavailable)
+            .header("retry-after", "1")
+            .body("server busy")
+            .build(),
+        )
+      }
+    },
+    None => None,
+  };
+  if let Err(reason) = check_signature(req) {
+    return Ok(
+      Response::builder(StatusCode::Forbidden)
+        .body(reason)
+        .build(),
+    );
+  }
+  match check_auth(req).await {
+    Some(challenge) => Ok(challenge),
+    None => respond(req).await,
+  }
+}
+
+/// 需要签名时校验签名，签名无效或过期时返回原因
+fn check_signature(req: &Request<AppConfig>) -> Result<(), &'static str> {
+  let Some(signer) = &req.state().signer else {
+    return Ok(());
+  };
+  if !signer.requires_signature(req.url().path()) {
+    return Ok(());
+  }
+  signer.verify(req.url(), unix_now())
+}
 
-    // println!("{} {:?} {:?}", url, root_dir, root_dir.join(url));
+/// 需要认证但没有通过时返回 401
+async fn check_auth(req: &Request<AppConfig>) -> Option<Response> {
+  let auth = req.state().auth.as_ref()?;
+  if !auth.requires_auth(req.url().path()) {
+    return None;
+  }
+  let authorization = req.header("authorization").map(|v| v.as_str());
+  match auth.verify(authorization).await {
+    Some(_) => None,
+    None => Some(auth.challenge()),
+  }
+}
 
-    match file_path.metadata().await {
-      Ok(stat) => {
-        if stat.is_dir() {
-          send(send_dir(&file_path, path).await, should_log_access, path)
-        } else if stat.is_file() {
-          send_file_304(&stat, &req, &file_path, should_log_access, path, gzip).await
-        } else {
-          send(Ok(Response::new(404)), should_log_access, path)
-        }
+/// peer_addr 形如 "127.0.0.1:52314" 或 "[::1]:52314"，访问日志只需要 ip 部分
+fn strip_port(addr: &str) -> &str {
+  match addr.rsplit_once(':') {
+    Some((ip, port)) if port.parse::<u16>().is_ok() => {
+      ip.trim_start_matches('[').trim_end_matches(']')
+    }
+    _ => addr,
+  }
+}
+
+/// --delay 和 --fault 的延迟、错误和限速
+async fn respond(req: &Request<AppConfig>) -> tide::Result {
+  let config = req.state();
+  let fault = config
+    .faults
+    .iter()
+    .find(|fault| fault.matches(req.url().path()));
+  let mut rng = Rng::new();
+  let mut delay = Duration::from_millis(config.delay);
+  if let Some(fault) = fault {
+    delay = delay.saturating_add(fault.latency(&mut rng));
+  }
+  if !delay.is_zero() {
+    async_std::task::sleep(delay).await;
+  }
+  if let Some(status) = fault.and_then(|fault| fault.error(&mut rng)) {
+    return Ok(Response::builder(status).body("injected fault").build());
+  }
+  let mut res = respond_site(req).await?;
+  if let Some(bandwidth) = fault.and_then(|fault| fault.bandwidth) {
+    throttle(&mut res, bandwidth);
+  }
+  Ok(res)
+}
+
+async fn respond_site(req: &Request<AppConfig>) -> tide::Result {
+  let config = req.state();
+  let path = req.url().path();
+  let Some((mount, url)) = config.find_mount(req.url().host_str(), path) else {
+    // 只有 base_href 下有内容时，和之前一样把根路径重定向过去
+    return Ok(match &config.base_href {
+      Some(base_href) if is_empty_root_url(path) => {
+        Response::builder(302).header("location", base_href).build()
       }
-      Err(_) => {
-        if !html5 || url.chars().rev().any(|c| c == '.') {
-          send(Ok(Response::new(404)), should_log_access, path)
-        } else {
-          let index_href = Path::new(index_href.as_path());
-          match index_href.metadata().await {
-            Ok(ref stat) => {
-              send_file_304(stat, &req, index_href, should_log_access, path, gzip).await
-            }
-            Err(err) => send(
-              Err(http_types::Error::new(StatusCode::InternalServerError, err)),
-              should_log_access,
-              path,
-            ),
-          }
+      Some(_) => Response::new(StatusCode::Forbidden),
+      None => Response::new(404),
+    });
+  };
+  let mut res = respond_mount(req, mount, path, url).await?;
+  if res.status().is_success() || res.status().is_redirection() {
+    for (name, value) in mount.headers.iter() {
+      res.insert_header(name.as_str(), value.as_str());
+    }
+  }
+  Ok(res)
+}
+
+async fn respond_mount(
+  req: &Request<AppConfig>,
+  mount: &Mount,
+  path: &str,
+  url: &str,
+) -> tide::Result {
+  let gzip = req.state().gzip;
+  let file_path = mount.root_dir.join(url);
+  if is_dir_mime_file(&file_path) {
+    return Ok(Response::new(404));
+  }
+
+  if let Some(cache_store) = &mount.cache_store {
+    return send_cache_file(req, cache_store, &file_path, mount.html5).await;
+  }
+
+  // println!("{} {:?} {:?}", url, root_dir, root_dir.join(url));
+
+  match file_path.metadata().await {
+    Ok(stat) if stat.is_dir() && mount.listing => send_dir(&file_path, path).await,
+    Ok(stat) if stat.is_file() => {
+      send_file_304(&stat, req, &mount.root_dir, &file_path, gzip).await
+    }
+    Ok(stat) if !stat.is_dir() => Ok(Response::new(404)),
+    // 不能列出文件的目录和不存在的文件一样处理
+    _ => {
+      if !mount.html5 || url.chars().rev().any(|c| c == '.') {
+        Ok(Response::new(404))
+      } else {
+        let index_href = Path::new(mount.index_href.as_path());
+        match index_href.metadata().await {
+          Ok(ref stat) => send_file_304(stat, req, &mount.root_dir, index_href, gzip).await,
+          Err(err) => Err(http_types::Error::new(StatusCode::InternalServerError, err)),
         }
       }
     }
+  }
+}
+
+/// --http-redirect-port 对应的 http 服务，把所有请求重定向到 https 端口（state 即 https 端口）。
+pub fn handle_https_redirect<'a>(
+  req: Request<u16>,
+  _: Next<'a, u16>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+  Box::pin(async move {
+    let https_port = *req.state();
+    let url = req.url();
+    let host = match url.host() {
+      Some(Host::Ipv6(ip)) => format!("[{}]", ip),
+      Some(host) => host.to_string(),
+      None => return Ok(Response::new(StatusCode::BadRequest)),
+    };
+    let mut location = if https_port == 443 {
+      format!("https://{}{}", host, url.path())
+    } else {
+      format!("https://{}:{}{}", host, https_port, url.path())
+    };
+    if let Some(query) = url.query() {
+      location.push('?');
+      location.push_str(query);
+    }
+    Ok(
+      Response::builder(StatusCode::MovedPermanently)
+        .header("location", location)
+        .build(),
+    )
   })
 }
+
+#[async_std::test]
+async fn test_delay() -> tide::Result<()> {
+  let root = std::env::temp_dir().join(format!("lightstatic-delay-{}", std::process::id()));
+  std::fs::create_dir_all(&root)?;
+  std::fs::write(root.join("index.html"), "<html></html>")?;
+  let config = crate::Config::builder(root.clone())
+    .delay(200)
+    .fault("latency=100,path=^/slow".parse().unwrap())
+    .build()
+    .await?;
+  let app = crate::server::static_files(std::sync::Arc::new(config));
+  let request = |path: &str| {
+    http_types::Request::new(
+      http_types::Method::Get,
+      http_types::Url::parse("http://localhost")
+        .unwrap()
+        .join(path)
+        .unwrap(),
+    )
+  };
+  // --delay 的单位是毫秒
+  let start = Instant::now();
+  let res: http_types::Response = app.respond(request("/index.html")).await?;
+  let elapsed = start.elapsed();
+  assert_eq!(StatusCode::Ok, res.status());
+  assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(2));
+  let start = Instant::now();
+  app
+    .respond::<_, http_types::Response>(request("/slow"))
+    .await?;
+  let elapsed = start.elapsed();
+  assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(2));
+  std::fs::remove_dir_all(&root)?;
+  Ok(())
+}
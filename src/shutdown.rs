@@ -0,0 +1,86 @@
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use async_std::channel::{bounded, Receiver, Sender};
+use futures::future::{select, Either};
+use log::{info, warn};
+
+use crate::pid::remove_pid;
+
+/// 不会发送任何消息，关闭 channel 即通知所有等待方开始退出
+static SHUTDOWN: LazyLock<(Sender<()>, Receiver<()>)> = LazyLock::new(|| bounded(1));
+/// 正在处理（包括正在发送响应体）的请求数
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// 第一次调用时停止接受新连接并开始等待处理中的请求；再次调用（如第二次 ctrl+c）立即退出。
+pub fn request_shutdown() {
+  if !SHUTDOWN.0.close() {
+    warn!(
+      "Forced exit with {} requests in flight",
+      IN_FLIGHT.load(Ordering::Relaxed)
+    );
+    remove_pid();
+    std::process::exit(1);
+  }
+}
+
+pub fn is_shutting_down() -> bool {
+  SHUTDOWN.0.is_closed()
+}
+
+/// fut 完成前收到退出请求时返回 None（fut 会被直接丢弃，只适合用于等待新连接、新请求这类可以中断的操作）
+pub async fn until_shutdown<F: Future>(fut: F) -> Option<F::Output> {
+  let stopped = async {
+    let _ = SHUTDOWN.1.recv().await;
+  };
+  match select(pin!(fut), pin!(stopped)).await {
+    Either::Left((output, _)) => Some(output),
+    Either::Right(_) => None,
+  }
+}
+
+/// 请求处理期间持有，drop 时计数减一
+pub struct InFlight(());
+
+impl InFlight {
+  pub fn start() -> Self {
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+    InFlight(())
+  }
+}
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// 等待处理中的请求全部完成，超时返回 false
+pub async fn drain(timeout: Duration) -> bool {
+  let deadline = Instant::now() + timeout;
+  let mut logged = false;
+  loop {
+    let in_flight = IN_FLIGHT.load(Ordering::Relaxed);
+    if in_flight == 0 {
+      return true;
+    }
+    if Instant::now() >= deadline {
+      warn!(
+        "Shutdown timeout, dropping {} requests in flight",
+        in_flight
+      );
+      return false;
+    }
+    if !logged {
+      info!(
+        "Waiting up to {:?} for {} requests in flight",
+        timeout, in_flight
+      );
+      logged = true;
+    }
+    async_std::task::sleep(Duration::from_millis(50)).await;
+  }
+}
@@ -0,0 +1,162 @@
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_std::path::Path;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use http_types::url::Position;
+use http_types::Url;
+use ring::hmac;
+
+use crate::args::{Args, Command};
+
+/// 带过期时间的签名链接，如 "/private/report.pdf?expires=1700000000&sig=..."。
+/// 签名为 HMAC-SHA256(secret, "{path}?expires={expires}")，path 是 url 编码后的路径，其它查询参数不参与签名。
+pub struct UrlSigner {
+  key: hmac::Key,
+  paths: Vec<String>,
+}
+
+impl UrlSigner {
+  /// paths 为空时所有路径都需要签名，否则只有这些路径前缀下的请求需要
+  pub fn new(secret: &str, paths: Vec<String>) -> Self {
+    UrlSigner {
+      key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+      paths,
+    }
+  }
+
+  pub fn requires_signature(&self, path: &str) -> bool {
+    self.paths.is_empty()
+      || self
+        .paths
+        .iter()
+        .any(|prefix| Path::new(path).starts_with(prefix))
+  }
+
+  fn signature(&self, path: &str, expires: u64) -> hmac::Tag {
+    hmac::sign(
+      &self.key,
+      format!("{}?expires={}", path, expires).as_bytes(),
+    )
+  }
+
+  /// 在 url 原有的查询参数后面加上 expires 和 sig
+  pub fn sign(&self, url: &mut Url, expires: u64) {
+    let sig = URL_SAFE_NO_PAD.encode(self.signature(url.path(), expires));
+    url
+      .query_pairs_mut()
+      .append_pair("expires", &expires.to_string())
+      .append_pair("sig", &sig);
+  }
+
+  /// 校验签名和过期时间，失败时返回原因
+  pub fn verify(&self, url: &Url, now: u64) -> Result<(), &'static str> {
+    let (mut expires, mut sig) = (None, None);
+    for (name, value) in url.query_pairs() {
+      match name.as_ref() {
+        "expires" => expires = Some(value),
+        "sig" => sig = Some(value),
+        _ => {}
+      }
+    }
+    let (Some(expires), Some(sig)) = (expires, sig) else {
+      return Err("missing signature");
+    };
+    let expires = expires.parse::<u64>().map_err(|_| "invalid expires")?;
+    let sig = URL_SAFE_NO_PAD
+      .decode(sig.as_bytes())
+      .map_err(|_| "invalid signature")?;
+    let message = format!("{}?expires={}", url.path(), expires);
+    // 先校验签名，避免伪造的链接得到 "link expired" 这样的提示
+    hmac::verify(&self.key, message.as_bytes(), &sig).map_err(|_| "invalid signature")?;
+    if expires < now {
+      return Err("link expired");
+    }
+    Ok(())
+  }
+}
+
+pub fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+/// `lightstatic sign`：target 为 "/path" 时输出带签名的路径和查询参数，为完整 url 时输出完整 url
+pub fn sign_url(signer: &UrlSigner, target: &str, expires_in: u64) -> Result<String, String> {
+  let full = target.starts_with("http://") || target.starts_with("https://");
+  let mut url = if full {
+    Url::parse(target)
+  } else if target.starts_with('/') {
+    Url::parse("http://localhost").and_then(|base| base.join(target))
+  } else {
+    return Err(format!(
+      "invalid path \"{}\", expect \"/PATH\" or a full url",
+      target
+    ));
+  }
+  .map_err(|err| format!("invalid url \"{}\": {}", target, err))?;
+  let expires = unix_now()
+    .checked_add(expires_in)
+    .ok_or_else(|| format!("expires in {} seconds is too far in the future", expires_in))?;
+  signer.sign(&mut url, expires);
+  Ok(if full {
+    url.to_string()
+  } else {
+    url[Position::BeforePath..].to_string()
+  })
+}
+
+/// 处理 `lightstatic sign` 子命令，把签名后的链接输出到 stdout
+pub fn handle_arg_sign(args: &Args) {
+  let Some(Command::Sign { path, expires }) = &args.command else {
+    return;
+  };
+  let signer = UrlSigner::new(args.sign_secret.as_deref().unwrap_or_default(), Vec::new());
+  match sign_url(&signer, path, *expires) {
+    Ok(url) => println!("{}", url),
+    Err(err) => {
+      eprintln!("error: {}", err);
+      process::exit(-1);
+    }
+  }
+}
+
+#[test]
+fn test_url_signer() {
+  let signer = UrlSigner::new("secret", vec!["/private".to_string()]);
+  assert!(signer.requires_signature("/private/a.pdf"));
+  assert!(signer.requires_signature("/private"));
+  assert!(!signer.requires_signature("/privates/a.pdf"));
+  assert!(UrlSigner::new("secret", Vec::new()).requires_signature("/"));
+
+  let mut url = Url::parse("http://localhost/private/a%20b.pdf?download=1").unwrap();
+  signer.sign(&mut url, 1000);
+  assert!(url.as_str().contains("?download=1&expires=1000&sig="));
+  assert_eq!(Ok(()), signer.verify(&url, 999));
+  assert_eq!(Ok(()), signer.verify(&url, 1000));
+  assert_eq!(Err("link expired"), signer.verify(&url, 1001));
+
+  let tampered = Url::parse(&url.as_str().replace("a%20b", "c")).unwrap();
+  assert_eq!(Err("invalid signature"), signer.verify(&tampered, 999));
+  let extended = Url::parse(&url.as_str().replace("expires=1000", "expires=2000")).unwrap();
+  assert_eq!(Err("invalid signature"), signer.verify(&extended, 999));
+  let other = UrlSigner::new("other", Vec::new());
+  assert_eq!(Err("invalid signature"), other.verify(&url, 999));
+  let unsigned = Url::parse("http://localhost/private/a.pdf").unwrap();
+  assert_eq!(Err("missing signature"), signer.verify(&unsigned, 999));
+
+  let signed = sign_url(&signer, "/private/a b.pdf", 60).unwrap();
+  assert!(signed.starts_with("/private/a%20b.pdf?expires="));
+  let url = Url::parse("http://example.com")
+    .unwrap()
+    .join(&signed)
+    .unwrap();
+  assert_eq!(Ok(()), signer.verify(&url, unix_now()));
+  let signed = sign_url(&signer, "https://example.com/private/a.pdf", 60).unwrap();
+  assert!(signed.starts_with("https://example.com/private/a.pdf?expires="));
+  assert!(sign_url(&signer, "private/a.pdf", 60).is_err());
+  assert!(sign_url(&signer, "/private/a.pdf", u64::MAX).is_err());
+}
@@ -1,6 +1,7 @@
 use crate::config::AppConfig;
-use crate::error;
-use crate::helper::{get_mime_from_ext, should_send_file};
+use crate::helper::should_send_file;
+use crate::metrics::METRICS;
+use crate::mime::{is_dir_mime_file, sniff};
 use ahash::AHashMap;
 use async_std::fs::File;
 use async_std::io::{ReadExt, Result};
@@ -9,12 +10,14 @@ use async_std::stream::StreamExt;
 use async_std::sync::RwLock;
 use futures::future::{BoxFuture, FutureExt};
 use futures::{AsyncBufRead, AsyncRead};
-use http_types::Body;
+use http_types::{Body, Mime};
+use log::{error, trace};
 use regex::Regex;
 use std::io::{ErrorKind, Write};
 use std::ops::Deref;
 use std::sync::Arc;
 use std::task::Poll;
+use std::time::Instant;
 // use std::time::SystemTime;
 // use async_compression::futures::write::GzipEncoder;
 // use async_compression::Level;
@@ -22,10 +25,12 @@ use flate2::write::GzEncoder;
 use flate2::Compression;
 use tide::{Request, Response};
 
+#[derive(Clone)]
 pub struct FileCache {
   buffer: Arc<Vec<u8>>,
   gzipped: bool,
-  file_ext: Option<String>,
+  /// 根据原始内容判断的类型，扩展名未知或有歧义时使用
+  sniffed: Option<Mime>,
   mtime: String,
   cache_forever: bool,
 }
@@ -69,13 +74,13 @@ impl AsyncBufRead for FileCacheReader {
     self: std::pin::Pin<&mut Self>,
     _: &mut std::task::Context<'_>,
   ) -> Poll<Result<&[u8]>> {
-    println!("poll_fill_buf");
+    trace!("poll_fill_buf");
     let rd = self.get_mut();
     Poll::Ready(Ok(&rd.buffer.deref()[rd.bytes_read..]))
   }
   fn consume(mut self: std::pin::Pin<&mut Self>, amt: usize) {
     self.bytes_read += amt;
-    println!(
+    trace!(
       "consume poll fill buf {} {} {}",
       amt,
       self.bytes_read,
@@ -85,6 +90,7 @@ impl AsyncBufRead for FileCacheReader {
 }
 
 type HMap = AHashMap<PathBuf, FileCache>;
+/// --cache-in-memory 的文件缓存，需要放在 `RwLock` 里给 `Config::cache_store` 使用
 pub struct FileCacheStore {
   store: HMap,
   index_file: FileCache,
@@ -97,9 +103,19 @@ impl FileCacheStore {
   pub fn len(&self) -> usize {
     self.store.len()
   }
+
+  pub fn is_empty(&self) -> bool {
+    self.store.is_empty()
+  }
+
+  /// 缓存占用的内存（压缩后的大小）
+  pub fn bytes(&self) -> usize {
+    self.store.values().map(|f| f.buffer.len()).sum::<usize>() + self.index_file.buffer.len()
+  }
 }
 
 pub async fn refresh_cache_store(store: &RwLock<FileCacheStore>) -> Option<usize> {
+  let start = Instant::now();
   let rd_store = store.read().await;
   if let Some(index_file) = read_file(
     Path::new(&rd_store.index_href),
@@ -137,10 +153,44 @@ pub async fn refresh_cache_store(store: &RwLock<FileCacheStore>) -> Option<usize
   for (fp, cf) in new_store.into_iter() {
     wt_store.store.insert(fp, cf); // 旧的 CacheFile 会被返回，然后自动 drop 清理
   }
+  METRICS.record_cache_refresh(start.elapsed());
   // return 会自动释放 write lock
   Some(wt_store.len())
 }
 
+/// 重新读取单个文件到缓存，文件已不存在时从缓存中移除，返回该文件当前是否在缓存中。
+pub async fn purge_cache_file(store: &RwLock<FileCacheStore>, file_path: &Path) -> bool {
+  let rd_store = store.read().await;
+  let is_index = file_path == rd_store.index_href;
+  let regex_immutable = rd_store.regex_immutable.clone();
+  drop(rd_store); // 读取文件期间不持有锁
+  let is_file = !is_dir_mime_file(file_path)
+    && file_path
+      .metadata()
+      .await
+      .map(|stat| stat.is_file())
+      .unwrap_or(false);
+  let cache_file = if is_file {
+    read_file(file_path, regex_immutable.as_ref()).await
+  } else {
+    None
+  };
+  let mut wt_store = store.write().await;
+  match cache_file {
+    Some(cache_file) => {
+      if is_index {
+        wt_store.index_file = cache_file.clone();
+      }
+      wt_store.store.insert(file_path.to_path_buf(), cache_file);
+      true
+    }
+    None => {
+      wt_store.store.remove(file_path);
+      false
+    }
+  }
+}
+
 async fn read_file(file_path: &Path, cache_forever_regexp: Option<&Regex>) -> Option<FileCache> {
   match async move {
     let stat = file_path.metadata().await?;
@@ -157,6 +207,7 @@ async fn read_file(file_path: &Path, cache_forever_regexp: Option<&Regex>) -> Op
     let mut gz = GzEncoder::new(Vec::new(), Compression::best());
     gz.write_all(&*buf)?;
     let c_buf = gz.finish()?;
+    let sniffed = sniff(&buf);
     // println!("end gzip file {}", SystemTime::now().duration_since(s).unwrap().as_secs());
 
     let (gzipped, buffer) = if c_buf.len() < buf.len() {
@@ -176,10 +227,7 @@ async fn read_file(file_path: &Path, cache_forever_regexp: Option<&Regex>) -> Op
     Result::<FileCache>::Ok(FileCache {
       buffer: Arc::new(buffer),
       gzipped,
-      file_ext: file_path
-        .extension()
-        .and_then(|p| p.to_str())
-        .map(String::from),
+      sniffed,
       mtime,
       cache_forever: file_path
         .to_str()
@@ -211,7 +259,7 @@ fn loop_read_dir<'a: 'f, 'f>(
         let stat = file_path.metadata().await?;
         if stat.is_dir() {
           loop_read_dir(&file_path, store, regex_immutable).await;
-        } else if stat.is_file() {
+        } else if stat.is_file() && !is_dir_mime_file(&file_path) {
           if let Some(cache_file) = read_file(&file_path, regex_immutable).await {
             store.insert(file_path, cache_file);
           }
@@ -231,27 +279,36 @@ fn loop_read_dir<'a: 'f, 'f>(
   .boxed()
 }
 
+/// 把 root_dir 下的所有文件读入内存，匹配 regex_immutable 的文件响应时带上永久缓存的 cache-control。
 pub async fn init_cache_store(
   index_href: PathBuf,
   root_dir: PathBuf,
   regex_immutable: Option<Regex>,
-) -> FileCacheStore {
+) -> Result<FileCacheStore> {
   let index_file = read_file(Path::new(&index_href), regex_immutable.as_ref())
     .await
-    .unwrap();
+    .ok_or_else(|| {
+      std::io::Error::new(
+        ErrorKind::NotFound,
+        format!("failed to read index file {}", index_href.display()),
+      )
+    })?;
 
   let mut store = HMap::new();
   if !(loop_read_dir(Path::new(&root_dir), &mut store, regex_immutable.as_ref()).await) {
-    panic!("failed to read static dir");
+    return Err(std::io::Error::other(format!(
+      "failed to read static dir {}",
+      root_dir.display()
+    )));
   }
 
-  FileCacheStore {
+  Ok(FileCacheStore {
     store,
     index_file,
     index_href,
     root_dir,
     regex_immutable,
-  }
+  })
 }
 
 pub async fn send_cache_file(
@@ -260,15 +317,20 @@ pub async fn send_cache_file(
   file_path: &Path,
   html5: bool,
 ) -> tide::Result<Response> {
+  if is_dir_mime_file(file_path) {
+    return Ok(Response::new(404));
+  }
   let rd_store = store.read().await;
   // println!("{:?}", file_path);
-  let cache_file = match &rd_store.store.get(file_path) {
-    Some(file) => file,
+  let cached = rd_store.store.get(file_path);
+  METRICS.record_cache(cached.is_some());
+  let (cache_file, cache_path) = match cached {
+    Some(file) => (file, file_path),
     None => {
       if !html5 {
         return Ok(Response::new(404));
       }
-      &rd_store.index_file
+      (&rd_store.index_file, rd_store.index_href.as_path())
     }
   };
   // println!("{}", cache_file.buffer.len());
@@ -282,9 +344,13 @@ pub async fn send_cache_file(
     }
 
     let mut body = Body::from_reader(cache_file.to_reader(), Some(cache_file.buffer.len()));
-    body.set_mime(get_mime_from_ext(
-      cache_file.file_ext.as_ref().map(|s| &s[..]),
-    ));
+    let config = req.state();
+    let sniffed = cache_file.sniffed.clone();
+    let mime = config
+      .mime
+      .detect(&rd_store.root_dir, cache_path, async { sniffed })
+      .await;
+    body.set_mime(mime);
     res.body(body).build()
   } else {
     Response::new(304)
@@ -295,3 +361,28 @@ pub async fn send_cache_file(
 async fn test_cache() -> Result<()> {
   panic!("")
 }
+
+#[async_std::test]
+async fn test_cache_skips_dir_mime_file() -> Result<()> {
+  let dir = std::env::temp_dir().join(format!("lightstatic-cache-mime-{}", std::process::id()));
+  std::fs::create_dir_all(dir.join("hls"))?;
+  std::fs::write(dir.join("index.html"), "<html></html>")?;
+  std::fs::write(dir.join("hls/a.ts"), "ts")?;
+  std::fs::write(
+    dir.join("hls").join(crate::mime::DIR_MIME_FILE),
+    "video/x-custom ts\n",
+  )?;
+  let root_dir = PathBuf::from(&dir);
+  let store =
+    RwLock::new(init_cache_store(root_dir.join("index.html"), root_dir.clone(), None).await?);
+  let mime_file = root_dir.join("hls").join(crate::mime::DIR_MIME_FILE);
+  assert!(store
+    .read()
+    .await
+    .store
+    .contains_key(&root_dir.join("hls/a.ts")));
+  assert!(!store.read().await.store.contains_key(&mime_file));
+  assert!(!purge_cache_file(&store, &mime_file).await);
+  std::fs::remove_dir_all(&dir)?;
+  Ok(())
+}
@@ -0,0 +1,165 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use async_std::net::TcpStream;
+use chrono::Datelike;
+use futures::{AsyncRead, AsyncWrite};
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+use log::info;
+use rcgen::{date_time_ymd, CertificateParams, KeyPair};
+use rustls::ServerConfig;
+
+const DEV_CERT_FILE: &str = "dev-cert.pem";
+const DEV_KEY_FILE: &str = "dev-key.pem";
+const DEV_HOSTS_FILE: &str = "dev-hosts.txt";
+/// 自签名证书的有效期，浏览器对超过 398 天的证书会直接拒绝。
+const DEV_CERT_DAYS: i64 = 397;
+
+/// 持有证书文件路径和当前生效的 rustls 配置，收到 SIGHUP 时可以重新加载证书。
+pub struct TlsStore {
+  cert_file: PathBuf,
+  key_file: PathBuf,
+  http2: bool,
+  server_config: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsStore {
+  pub fn new(cert_file: PathBuf, key_file: PathBuf, http2: bool) -> io::Result<Self> {
+    let server_config = load_server_config(&cert_file, &key_file, http2)?;
+    Ok(TlsStore {
+      cert_file,
+      key_file,
+      http2,
+      server_config: RwLock::new(Arc::new(server_config)),
+    })
+  }
+
+  /// 重新读取证书和私钥，失败时保留旧的配置。
+  pub fn reload(&self) -> io::Result<()> {
+    let server_config = load_server_config(&self.cert_file, &self.key_file, self.http2)?;
+    *self.server_config.write().unwrap() = Arc::new(server_config);
+    Ok(())
+  }
+
+  pub fn acceptor(&self) -> TlsAcceptor {
+    TlsAcceptor::from(self.server_config.read().unwrap().clone())
+  }
+}
+
+fn load_server_config(cert_file: &Path, key_file: &Path, http2: bool) -> io::Result<ServerConfig> {
+  // 证书文件可以包含完整的证书链，按顺序全部读取
+  let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file)?))
+    .collect::<io::Result<Vec<_>>>()?;
+  if certs.is_empty() {
+    return Err(io::Error::new(
+      ErrorKind::InvalidData,
+      format!("no certificate found in {}", cert_file.display()),
+    ));
+  }
+  let key =
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(key_file)?))?.ok_or_else(|| {
+      io::Error::new(
+        ErrorKind::InvalidData,
+        format!("no private key found in {}", key_file.display()),
+      )
+    })?;
+  let mut config = ServerConfig::builder()
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+  config.alpn_protocols = if http2 {
+    vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+  } else {
+    vec![b"http/1.1".to_vec()]
+  };
+  Ok(config)
+}
+
+/// 返回 --https-dev 使用的自签名证书和私钥路径。
+///
+/// 证书缓存在用户配置目录下，仅当覆盖的主机列表变化（比如局域网 ip 变了）或证书快过期时才重新生成。
+pub fn ensure_dev_cert(hosts: &[String]) -> io::Result<(PathBuf, PathBuf)> {
+  let dir = dirs::config_dir()
+    .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "user config directory not found"))?
+    .join("lightstatic");
+  let cert_file = dir.join(DEV_CERT_FILE);
+  let key_file = dir.join(DEV_KEY_FILE);
+  let hosts_file = dir.join(DEV_HOSTS_FILE);
+  let hosts_line = hosts.join(" ");
+
+  let expire_soon = SystemTime::now() - Duration::from_secs((DEV_CERT_DAYS as u64 - 7) * 86400);
+  let reusable = key_file.is_file()
+    && fs::read_to_string(&hosts_file).is_ok_and(|cached| cached == hosts_line)
+    && fs::metadata(&cert_file)
+      .and_then(|stat| stat.modified())
+      .is_ok_and(|mtime| mtime > expire_soon);
+  if reusable {
+    return Ok((cert_file, key_file));
+  }
+
+  let to_io_err = |err: rcgen::Error| io::Error::other(err);
+  let key_pair = KeyPair::generate().map_err(to_io_err)?;
+  let mut params = CertificateParams::new(hosts.to_vec()).map_err(to_io_err)?;
+  let expire = chrono::Utc::now() + chrono::Duration::days(DEV_CERT_DAYS);
+  params.not_after = date_time_ymd(expire.year(), expire.month() as u8, expire.day() as u8);
+  let cert = params.self_signed(&key_pair).map_err(to_io_err)?;
+
+  fs::create_dir_all(&dir)?;
+  // 私钥只允许当前用户读写
+  File::options()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(0o600)
+    .open(&key_file)
+    .and_then(|mut f| io::Write::write_all(&mut f, key_pair.serialize_pem().as_bytes()))?;
+  fs::write(&cert_file, cert.pem())?;
+  fs::write(&hosts_file, hosts_line)?;
+  info!(
+    "Generated self-signed certificate {} for {}",
+    cert_file.display(),
+    hosts.join(", ")
+  );
+  Ok((cert_file, key_file))
+}
+
+/// async-h1 要求 stream 可以 Clone（读写分别持有一份），因此用 Arc<Mutex> 包装 TlsStream。
+#[derive(Clone)]
+pub struct TlsStreamWrapper(Arc<Mutex<TlsStream<TcpStream>>>);
+
+impl TlsStreamWrapper {
+  pub fn new(stream: TlsStream<TcpStream>) -> Self {
+    TlsStreamWrapper(Arc::new(Mutex::new(stream)))
+  }
+}
+
+impl AsyncRead for TlsStreamWrapper {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut *self.0.lock().unwrap()).poll_read(cx, buf)
+  }
+}
+
+impl AsyncWrite for TlsStreamWrapper {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+  }
+
+  fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut *self.0.lock().unwrap()).poll_close(cx)
+  }
+}
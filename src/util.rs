@@ -38,6 +38,102 @@ pub fn is_empty_root_url(p: &str) -> bool {
   p.chars().next().map(|c| c == '/').unwrap_or(true)
 }
 
+/// base href 统一为 "/xxx/" 的形式，"" 和 "/" 返回 None
+pub fn normalize_base_href(base_href: &str) -> Option<String> {
+  let base_href = base_href.trim();
+  if is_empty_root_url(base_href) {
+    return None;
+  }
+  let mut normalized = String::with_capacity(base_href.len() + 2);
+  if !base_href.starts_with('/') {
+    normalized.push('/');
+  }
+  normalized.push_str(base_href);
+  if !normalized.ends_with('/') {
+    normalized.push('/');
+  }
+  Some(normalized)
+}
+
+/// 比较耗时只和长度有关，避免通过响应时间逐字节猜测 token。
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 解析 "1024"、"512K"、"10M"、"1G" 这样的大小参数，单位为 1024 进制。
+pub fn parse_size(s: &str) -> Result<u64, String> {
+  let s = s.trim();
+  let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+    Some(idx) => s.split_at(idx),
+    None => (s, ""),
+  };
+  let unit = match unit.to_ascii_uppercase().as_str() {
+    "" | "B" => 1,
+    "K" | "KB" => 1 << 10,
+    "M" | "MB" => 1 << 20,
+    "G" | "GB" => 1 << 30,
+    _ => return Err(format!("invalid size unit \"{}\"", unit)),
+  };
+  num
+    .parse::<u64>()
+    .ok()
+    .and_then(|n| n.checked_mul(unit))
+    .ok_or_else(|| format!("invalid size \"{}\"", s))
+}
+
+/// 解析 "3600"、"30m"、"12h"、"7d" 这样的时长参数，返回秒数，不带单位时为秒。
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+  let s = s.trim();
+  let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+    Some(idx) => s.split_at(idx),
+    None => (s, ""),
+  };
+  let unit = match unit.to_ascii_lowercase().as_str() {
+    "" | "s" => 1,
+    "m" => 60,
+    "h" => 60 * 60,
+    "d" => 24 * 60 * 60,
+    _ => return Err(format!("invalid duration unit \"{}\"", unit)),
+  };
+  num
+    .parse::<u64>()
+    .ok()
+    .and_then(|n| n.checked_mul(unit))
+    .ok_or_else(|| format!("invalid duration \"{}\"", s))
+}
+
+#[test]
+fn test_parse_size() {
+  assert_eq!(Ok(1024), parse_size("1024"));
+  assert_eq!(Ok(512 * 1024), parse_size("512K"));
+  assert_eq!(Ok(10 * 1024 * 1024), parse_size("10mb"));
+  assert_eq!(Ok(1 << 30), parse_size("1G"));
+  assert!(parse_size("10T").is_err());
+  assert!(parse_size("M").is_err());
+  assert!(parse_size("99999999999999999G").is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+  assert_eq!(Ok(90), parse_duration("90"));
+  assert_eq!(Ok(30 * 60), parse_duration("30m"));
+  assert_eq!(Ok(12 * 3600), parse_duration("12H"));
+  assert_eq!(Ok(7 * 86400), parse_duration("7d"));
+  assert!(parse_duration("1w").is_err());
+  assert!(parse_duration("h").is_err());
+}
+
+#[test]
+fn test_normalize_base_href() {
+  assert_eq!(None, normalize_base_href(""));
+  assert_eq!(None, normalize_base_href(" / "));
+  assert_eq!(Some("/app/".into()), normalize_base_href("app"));
+  assert_eq!(Some("/app/".into()), normalize_base_href("/app/"));
+}
+
 #[test]
 fn test_resolve_path() {
   let home = PathBuf::from(dirs::home_dir().unwrap());
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::mount::{Mount, MountSpec};
+
+/// 虚拟主机名，"example.com" 精确匹配，"*.example.com" 匹配任意层级的子域名（不含 example.com 本身）
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostPattern {
+  Exact(String),
+  /// 保存的是 ".example.com"
+  Wildcard(String),
+}
+
+impl HostPattern {
+  pub fn matches(&self, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    match self {
+      HostPattern::Exact(name) => host.eq_ignore_ascii_case(name),
+      HostPattern::Wildcard(suffix) => {
+        host.len() > suffix.len()
+          && host.is_char_boundary(host.len() - suffix.len())
+          && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+      }
+    }
+  }
+
+  /// 排序用，越小越优先
+  pub fn priority(&self) -> (bool, std::cmp::Reverse<usize>) {
+    match self {
+      HostPattern::Exact(name) => (false, std::cmp::Reverse(name.len())),
+      HostPattern::Wildcard(suffix) => (true, std::cmp::Reverse(suffix.len())),
+    }
+  }
+}
+
+impl FromStr for HostPattern {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let host = s.trim().trim_end_matches('.').to_ascii_lowercase();
+    let (wildcard, name) = match host.strip_prefix("*.") {
+      Some(name) => (true, name),
+      None => (false, host.as_str()),
+    };
+    let valid = !name.is_empty()
+      && name.split('.').all(|label| {
+        !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+      });
+    if !valid {
+      return Err(format!(
+        "invalid host \"{}\", expect a name like \"example.com\" or \"*.example.com\"",
+        s
+      ));
+    }
+    Ok(if wildcard {
+      HostPattern::Wildcard(format!(".{}", name))
+    } else {
+      HostPattern::Exact(name.to_string())
+    })
+  }
+}
+
+impl fmt::Display for HostPattern {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      HostPattern::Exact(name) => f.write_str(name),
+      HostPattern::Wildcard(suffix) => write!(f, "*{}", suffix),
+    }
+  }
+}
+
+/// --vhost 的参数，如 "*.example.com=./sites/example,html5,cache"，选项和 --mount 相同
+#[derive(Clone, Debug, PartialEq)]
+pub struct VhostSpec {
+  pub host: HostPattern,
+  /// 挂载在 "/" 的站点根目录
+  pub mount: MountSpec,
+}
+
+impl FromStr for VhostSpec {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let Some((host, rest)) = s.split_once('=') else {
+      return Err(format!(
+        "invalid virtual host \"{}\", expect \"HOST=DIR[,OPTION...]\"",
+        s
+      ));
+    };
+    Ok(VhostSpec {
+      host: host.parse()?,
+      mount: format!("/={}", rest).parse()?,
+    })
+  }
+}
+
+/// Host 匹配时使用的站点
+pub struct VirtualHost {
+  pub host: HostPattern,
+  pub mounts: Vec<Mount>,
+}
+
+#[test]
+fn test_host_pattern() {
+  let exact: HostPattern = "Example.com".parse().unwrap();
+  assert!(exact.matches("example.com"));
+  assert!(exact.matches("EXAMPLE.com."));
+  assert!(!exact.matches("www.example.com"));
+
+  let wildcard: HostPattern = "*.example.com".parse().unwrap();
+  assert_eq!("*.example.com", wildcard.to_string());
+  assert!(wildcard.matches("www.example.com"));
+  assert!(wildcard.matches("a.b.Example.com"));
+  assert!(!wildcard.matches("example.com"));
+  assert!(!wildcard.matches("badexample.com"));
+  assert!(exact.priority() < wildcard.priority());
+  assert!("*.a.example.com".parse::<HostPattern>().unwrap().priority() < wildcard.priority());
+
+  assert!("".parse::<HostPattern>().is_err());
+  assert!("*".parse::<HostPattern>().is_err());
+  assert!("a..com".parse::<HostPattern>().is_err());
+  assert!("a.com:8080".parse::<HostPattern>().is_err());
+
+  let spec: VhostSpec = "blog.example.com=./blog,html5".parse().unwrap();
+  assert_eq!("/", spec.mount.prefix);
+  assert_eq!("./blog", spec.mount.dir);
+  assert!(spec.mount.html5);
+  assert!("blog.example.com".parse::<VhostSpec>().is_err());
+}